@@ -4,8 +4,11 @@ use clap::{
     Args,
     Parser,
     Subcommand,
+    ValueEnum,
 };
 
+use rust_intersection_painting::{generators, stenciler};
+
 #[derive(Debug, Parser)]
 #[clap(author, version, about)]
 pub struct Arguments {
@@ -19,7 +22,11 @@ pub struct Arguments {
 pub enum GeneratorType{
     Dynamic(DynamicCommand),
     Static(StaticCommand),
-    GenerateStencil(GenerateStencilCommand)
+    GenerateStencil(GenerateStencilCommand),
+    PreviewStencil(PreviewStencilCommand),
+    InspectStencil(InspectStencilCommand),
+    TransformStencil(TransformStencilCommand),
+    CombineStencils(CombineStencilsCommand)
 }
 
 #[derive(Debug, Args)]
@@ -30,9 +37,15 @@ pub struct DynamicCommand{
     /// Output Path, folder or file
     pub output: PathBuf,
 
-    /// Alpha averaging enabled?
-    #[arg(short, long)]
-    pub alpha_averaging: bool,
+    #[command(flatten)]
+    pub painting: PaintingArgs,
+
+    /// How many images to process at once, for folder inputs. Defaults to one per CPU core.
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub jobs: Option<u32>,
+
+    #[command(flatten)]
+    pub transform: TransformArgs,
 
     #[clap(subcommand)]
     pub generator: Generator,
@@ -40,18 +53,130 @@ pub struct DynamicCommand{
 
 #[derive(Debug, Args)]
 pub struct StaticCommand{
-    /// Stencil Path
+    /// Stencil Path, a lossless image or a .stencil file. Images hold up to 16,777,216 segments, .stencil files up to 4,294,967,296.
     pub stencil: PathBuf,
 
-    /// Alpha averaging enabled?
-    #[arg(short, long)]
-    pub alpha_averaging: bool,
+    #[command(flatten)]
+    pub painting: PaintingArgs,
 
     /// Input Path
     pub input: PathBuf,
 
     /// Output Path
-    pub output: PathBuf
+    pub output: PathBuf,
+
+    /// Read the stencil and input in strips instead of all at once, for images too big to fit in memory. PNG or .stencil stencils and PNG input only, mean aggregation only, no outlines.
+    #[arg(long)]
+    pub stream: bool,
+
+    /// Rows per strip when streaming
+    #[arg(long, default_value_t = 256, value_parser = clap::value_parser!(u32).range(1..), requires = "stream")]
+    pub strip_rows: u32
+}
+
+// Options shared by every command that paints through a stencil.
+#[derive(Debug, Args)]
+pub struct PaintingArgs{
+    /// Alpha averaging enabled?
+    #[arg(short, long)]
+    pub alpha_averaging: bool,
+
+    /// How should each segment's pixels be combined into one colour?
+    #[arg(long, value_enum, default_value_t = Aggregation::Mean)]
+    pub aggregation: Aggregation,
+
+    /// Which colour space should means be taken in? Other aggregations always use sRGB.
+    #[arg(long, value_enum, default_value_t = ColourSpace::Srgb)]
+    pub colour_space: ColourSpace,
+
+    /// Only fill segments with at least this much ink. Either a fraction of the segment's area (e.g. `0.25`) or a pixel count (e.g. `40px`).
+    #[arg(long, value_parser = parse_coverage)]
+    pub min_coverage: Option<stenciler::Coverage>,
+
+    /// What unfilled segments look like: `transparent`, `passthrough` to keep the input pixels, or an RRGGBB/RRGGBBAA hex colour
+    #[arg(long, value_parser = parse_background, default_value = "000000ff")]
+    pub background: stenciler::Background,
+
+    /// Outline segments in this RRGGBB/RRGGBBAA hex colour
+    #[arg(long, value_parser = parse_colour)]
+    pub outline: Option<[u8; 4]>,
+
+    /// Outline thickness in pixels
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub outline_thickness: u32,
+
+    /// Which segments get outlined?
+    #[arg(long, value_enum, default_value_t = OutlineSegments::All)]
+    pub outline_segments: OutlineSegments,
+
+    /// Split every segment into its connected pieces before painting, so each piece gets its own colour
+    #[arg(long, value_enum)]
+    pub split_components: Option<Connectivity>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutlineSegments{
+    /// Every segment boundary
+    All,
+    /// Only boundaries touching a filled segment
+    Painted
+}
+
+fn parse_background(s: &str) -> Result<stenciler::Background, String>{
+    match s{
+        "transparent" => Ok(stenciler::Background::Colour([0, 0, 0, 0])),
+        "passthrough" => Ok(stenciler::Background::Passthrough),
+        colour => parse_colour(colour).map(stenciler::Background::Colour)
+    }
+}
+
+fn parse_coverage(s: &str) -> Result<stenciler::Coverage, String>{
+    if let Some(pixels) = s.strip_suffix("px"){
+        return pixels.parse().map(stenciler::Coverage::Pixels).map_err(|e| format!("Invalid pixel count: {}", e));
+    }
+
+    let fraction: f32 = s.parse().map_err(|e| format!("Invalid fraction: {}", e))?;
+    if !(0.0..=1.0).contains(&fraction){
+        return Err(format!("Coverage fraction {} isn't between 0 and 1", fraction));
+    }
+    return Ok(stenciler::Coverage::Fraction(fraction));
+}
+
+fn parse_colour(s: &str) -> Result<[u8; 4], String>{
+    let hex = s.trim_start_matches('#');
+    if (hex.len() != 6 && hex.len() != 8) || !hex.is_ascii(){
+        return Err(format!("Expected RRGGBB or RRGGBBAA, got {}", s));
+    }
+
+    let mut colour = [255u8; 4];
+    for (i, channel) in colour.iter_mut().take(hex.len() / 2).enumerate(){
+        *channel = u8::from_str_radix(&hex[2*i..2*i + 2], 16).map_err(|e| format!("Invalid hex colour {}: {}", s, e))?;
+    }
+    return Ok(colour);
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Aggregation{
+    /// Arithmetic mean of each channel
+    Mean,
+    /// Median of each channel
+    Median,
+    /// Most frequent colour
+    Mode,
+    /// Minimum of each channel
+    Min,
+    /// Maximum of each channel
+    Max
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColourSpace{
+    /// Raw sRGB bytes
+    Srgb,
+    /// Linear light RGB
+    Linear,
+    /// Perceptually uniform OKLab
+    Oklab
 }
 
 #[derive(Debug, Args)]
@@ -62,44 +187,312 @@ pub struct GenerateStencilCommand{
     /// Height
     pub height: u32,
 
-    /// Output Path
+    /// Output Path. Use a .stencil extension for the native format, or a lossless image format like PNG. Images hold up to 16,777,216 segments, .stencil files up to 4,294,967,296.
     pub output: PathBuf,
 
+    /// Compress .stencil output
+    #[arg(short, long)]
+    pub compress: bool,
+
+    /// Also save a colourised preview of the stencil here
+    #[arg(long)]
+    pub preview: Option<PathBuf>,
+
+    /// RNG seed for the preview colours. Random if not given.
+    #[arg(long, requires = "preview")]
+    pub preview_seed: Option<u64>,
+
+    #[command(flatten)]
+    pub transform: TransformArgs,
+
     #[clap(subcommand)]
     pub generator: Generator,
 }
 
+#[derive(Debug, Args)]
+pub struct PreviewStencilCommand{
+    /// Stencil Path
+    pub stencil: PathBuf,
+
+    /// Output Path
+    pub output: PathBuf,
+
+    /// RNG seed for the colours. Random if not given.
+    #[arg(short, long)]
+    pub seed: Option<u64>
+}
+
+#[derive(Debug, Args)]
+pub struct InspectStencilCommand{
+    /// Stencil Path
+    pub stencil: PathBuf,
+
+    /// Report format
+    #[arg(short, long, value_enum, default_value_t = ReportFormat::Text)]
+    pub format: ReportFormat
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ReportFormat{
+    Text,
+    Json
+}
+
+#[derive(Debug, Args)]
+pub struct TransformStencilCommand{
+    /// Stencil Path
+    pub stencil: PathBuf,
+
+    /// Output Path, a lossless image or a .stencil file
+    pub output: PathBuf,
+
+    /// Compress .stencil output
+    #[arg(short, long)]
+    pub compress: bool,
+
+    #[clap(subcommand)]
+    pub transform: StencilTransform
+}
+
+#[derive(Debug, Subcommand)]
+pub enum StencilTransform{
+    /// Renumber the used segment indices to 0..n
+    Relabel,
+    /// Give every connected piece of every segment its own index
+    SplitComponents(SplitComponentsCommand),
+    /// Merge segments smaller than a minimum area into a neighbour
+    MergeSmall(MergeSmallCommand)
+}
+
+#[derive(Debug, Args)]
+pub struct MergeSmallCommand{
+    /// Segments with fewer pixels than this get merged
+    #[arg(value_parser = clap::value_parser!(u64).range(1..))]
+    pub min_area: u64,
+
+    /// Merge into the most similarly coloured neighbour in this image, instead of the largest neighbour
+    #[arg(short, long)]
+    pub image: Option<PathBuf>
+}
+
+#[derive(Debug, Args)]
+pub struct SplitComponentsCommand{
+    /// Which neighbours count as connected?
+    #[arg(value_enum, default_value_t = Connectivity::Four)]
+    pub connectivity: Connectivity
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Connectivity{
+    /// Edges only
+    Four,
+    /// Edges and corners
+    Eight
+}
+
+// Overlays two stencils, so every overlap of a segment from each becomes its own segment.
+#[derive(Debug, Args)]
+pub struct CombineStencilsCommand{
+    /// First Stencil Path
+    pub first: PathBuf,
+
+    /// Second Stencil Path
+    pub second: PathBuf,
+
+    /// Output Path, a lossless image or a .stencil file
+    pub output: PathBuf,
+
+    /// Compress .stencil output
+    #[arg(short, long)]
+    pub compress: bool
+}
+
+// Moves the generator's pattern around, about the centre of the canvas. Works with every generator but slic.
+#[derive(Debug, Args)]
+pub struct TransformArgs{
+    /// Rotate the pattern clockwise by this many degrees
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub rotate: f32,
+
+    /// Scale the pattern by this factor
+    #[arg(long, default_value_t = 1.0, value_parser = parse_scale)]
+    pub scale: f32,
+
+    /// Move the pattern right by this many pixels
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub offset_x: f32,
+
+    /// Move the pattern down by this many pixels
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub offset_y: f32,
+
+    /// Mirror the pattern left to right
+    #[arg(long)]
+    pub mirror_x: bool,
+
+    /// Mirror the pattern top to bottom
+    #[arg(long)]
+    pub mirror_y: bool
+}
+
+fn parse_scale(s: &str) -> Result<f32, String>{
+    let scale: f32 = s.parse().map_err(|e| format!("Invalid scale: {}", e))?;
+    if !(scale.is_finite() && scale > 0.0){
+        return Err(format!("Scale {} isn't a positive number", scale));
+    }
+    return Ok(scale);
+}
+
 #[derive(Debug, Subcommand)]
 pub enum Generator{
     SquareGrid(SquareGridCommand),
     CircleGrid(CircleGridCommand),
     ConcentricCircleGrid(ConcentricCircleGridCommand),
     CrossGrid(CrossGridCommand),
+    HexGrid(HexGridCommand),
+    Voronoi(VoronoiCommand),
+    Slic(SlicCommand),
     MaskGrid(MaskGridCommand),
-    FloodFill(FloodFillCommand)
+    FloodFill(FloodFillCommand),
+    RadialSectors(RadialSectorsCommand),
+    TriangleGrid(TilingCommand),
+    RhombilleGrid(TilingCommand),
+    KisrhombilleGrid(TilingCommand),
+    Brick(BrickCommand),
+    Herringbone(RectangleTilingCommand),
+    Basketweave(RectangleTilingCommand),
+    Penrose(PenroseCommand)
 }
 
 #[derive(Debug, Args)]
 pub struct SquareGridCommand{
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
     pub side_length: u32
 }
 
 #[derive(Debug, Args)]
 pub struct CircleGridCommand{
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
     pub radius: u32
 }
 
 #[derive(Debug, Args)]
 pub struct ConcentricCircleGridCommand{
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
     pub radius: u32
 }
 
 
 #[derive(Debug, Args)]
 pub struct CrossGridCommand{
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
     pub cross_intersection_width: u32
 }
 
+#[derive(Debug, Args)]
+pub struct HexGridCommand{
+    /// Circumradius of each hexagon
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+    pub radius: u32,
+
+    /// Flat-top or pointy-top hexagons?
+    #[arg(short, long, value_enum, default_value_t = HexOrientation::FlatTop)]
+    pub orientation: HexOrientation
+}
+
+#[derive(Debug, Args)]
+pub struct BrickCommand{
+    #[command(flatten)]
+    pub brick: RectangleTilingCommand,
+
+    /// How far along each row is shifted from the one above, as a fraction of a brick
+    #[arg(short, long, default_value_t = 0.5)]
+    pub offset_ratio: f32
+}
+
+#[derive(Debug, Args)]
+pub struct PenroseCommand{
+    /// Tile edge length. For kites and darts, the long edge.
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+    pub edge_length: u32,
+
+    /// Which Penrose tiling?
+    #[arg(short, long, value_enum, default_value_t = PenroseTiling::Rhombs)]
+    pub tiling: PenroseTiling
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum PenroseTiling{
+    /// P2
+    #[value(alias = "p2")]
+    KitesAndDarts,
+    /// P3
+    #[value(alias = "p3")]
+    Rhombs
+}
+
+// Shared by the rectangle based tilings
+#[derive(Debug, Args)]
+pub struct RectangleTilingCommand{
+    /// Brick width
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+    pub brick_width: u32,
+
+    /// Brick height
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+    pub brick_height: u32
+}
+
+// Shared by the triangle and hexagon based tilings
+#[derive(Debug, Args)]
+pub struct TilingCommand{
+    /// Tile edge length. For kisrhombille, the long edge of each triangle.
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+    pub edge_length: u32,
+
+    /// Flat-top or pointy-top? Pointy-top tilings are flat-top ones turned on their side.
+    #[arg(short, long, value_enum, default_value_t = HexOrientation::FlatTop)]
+    pub orientation: HexOrientation
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum HexOrientation{
+    FlatTop,
+    PointyTop
+}
+
+#[derive(Debug, Args)]
+pub struct VoronoiCommand{
+    /// Number of seed points (approximate for poisson-disc)
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+    pub num_points: u32,
+
+    /// How should the seed points be scattered?
+    #[arg(short, long, value_enum, default_value_t = PointDistribution::Uniform)]
+    pub distribution: PointDistribution,
+
+    /// RNG seed, for reproducible stencils. Random if not given.
+    #[arg(short, long)]
+    pub seed: Option<u64>
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum PointDistribution{
+    Uniform,
+    PoissonDisc
+}
+
+#[derive(Debug, Args)]
+pub struct SlicCommand{
+    /// Target number of superpixels
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+    pub num_segments: u32,
+
+    /// Spatial vs. colour weighting. Higher values give more compact, regular segments.
+    #[arg(short, long, default_value_t = 10.0)]
+    pub compactness: f32
+}
+
 #[derive(Debug, Args)]
 pub struct MaskGridCommand{
     pub mask_folder: PathBuf
@@ -108,4 +501,144 @@ pub struct MaskGridCommand{
 #[derive(Debug, Args)]
 pub struct FloodFillCommand{
     pub mask_path: PathBuf
+}
+
+#[derive(Debug, Args)]
+pub struct RadialSectorsCommand{
+    /// Number of wedges
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+    pub num_sectors: u32,
+
+    /// Centre x coordinate. Defaults to the middle of the canvas.
+    #[arg(short = 'x', long, allow_negative_numbers = true, requires = "centre_y")]
+    pub centre_x: Option<f32>,
+
+    /// Centre y coordinate. Defaults to the middle of the canvas.
+    #[arg(short = 'y', long, allow_negative_numbers = true, requires = "centre_x")]
+    pub centre_y: Option<f32>,
+
+    /// Angle the first wedge starts at, in degrees clockwise from pointing right
+    #[arg(short, long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub start_angle: f32,
+
+    /// Also split into rings of this width, for a dartboard
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub ring_width: Option<u32>
+}
+
+// Conversions into library types
+impl From<PaintingArgs> for stenciler::PipelineOptions{
+    fn from(painting: PaintingArgs) -> Self{
+        stenciler::PipelineOptions{
+            alpha_averaging: painting.alpha_averaging,
+            aggregation: painting.aggregation.into(),
+            colour_space: painting.colour_space.into(),
+            min_coverage: painting.min_coverage,
+            background: painting.background,
+            outline: painting.outline.map(|colour| stenciler::OutlineOptions{colour, thickness: painting.outline_thickness, segments: painting.outline_segments.into()}),
+            split_components: painting.split_components.map(Into::into)
+        }
+    }
+}
+
+impl From<Aggregation> for stenciler::Aggregation{
+    fn from(aggregation: Aggregation) -> Self{
+        match aggregation{
+            Aggregation::Mean => stenciler::Aggregation::Mean,
+            Aggregation::Median => stenciler::Aggregation::Median,
+            Aggregation::Mode => stenciler::Aggregation::Mode,
+            Aggregation::Min => stenciler::Aggregation::Min,
+            Aggregation::Max => stenciler::Aggregation::Max
+        }
+    }
+}
+
+impl From<ColourSpace> for stenciler::ColourSpace{
+    fn from(colour_space: ColourSpace) -> Self{
+        match colour_space{
+            ColourSpace::Srgb => stenciler::ColourSpace::Srgb,
+            ColourSpace::Linear => stenciler::ColourSpace::Linear,
+            ColourSpace::Oklab => stenciler::ColourSpace::Oklab
+        }
+    }
+}
+
+impl From<OutlineSegments> for stenciler::OutlineSegments{
+    fn from(segments: OutlineSegments) -> Self{
+        match segments{
+            OutlineSegments::All => stenciler::OutlineSegments::All,
+            OutlineSegments::Painted => stenciler::OutlineSegments::Painted
+        }
+    }
+}
+
+impl From<Connectivity> for generators::Connectivity{
+    fn from(connectivity: Connectivity) -> Self{
+        match connectivity{
+            Connectivity::Four => generators::Connectivity::Four,
+            Connectivity::Eight => generators::Connectivity::Eight
+        }
+    }
+}
+
+impl TransformArgs{
+    // Only wraps the generator when there's something to do.
+    pub fn apply(self, generator: generators::Generator) -> generators::Generator{
+        let transform = generators::Transform{rotation: self.rotate, scale: self.scale, offset: (self.offset_x, self.offset_y), mirror_x: self.mirror_x, mirror_y: self.mirror_y};
+        if transform.is_identity(){
+            return generator;
+        }
+        return generators::Generator::Transformed(generators::Transformed{generator: Box::new(generator), transform});
+    }
+}
+
+impl From<Generator> for generators::Generator{
+    fn from(generator: Generator) -> Self{
+        match generator{
+            Generator::SquareGrid(SquareGridCommand{side_length}) => generators::Generator::SquareGrid(generators::SquareGrid{side_length}),
+            Generator::CircleGrid(CircleGridCommand{radius}) => generators::Generator::CircleGrid(generators::CircleGrid{radius}),
+            Generator::ConcentricCircleGrid(ConcentricCircleGridCommand{radius}) => generators::Generator::ConcentricCircleGrid(generators::ConcentricCircleGrid{radius}),
+            Generator::CrossGrid(CrossGridCommand{cross_intersection_width}) => generators::Generator::CrossGrid(generators::CrossGrid{cross_intersection_width}),
+            Generator::HexGrid(HexGridCommand{radius, orientation}) => generators::Generator::HexGrid(generators::HexGrid{radius, orientation: orientation.into()}),
+            Generator::Voronoi(VoronoiCommand{num_points, distribution, seed}) => generators::Generator::Voronoi(generators::Voronoi{num_points, distribution: distribution.into(), seed}),
+            Generator::Slic(SlicCommand{num_segments, compactness}) => generators::Generator::Slic(generators::Slic{num_segments, compactness}),
+            Generator::MaskGrid(MaskGridCommand{mask_folder}) => generators::Generator::MaskGrid(generators::MaskGrid{mask_folder}),
+            Generator::FloodFill(FloodFillCommand{mask_path}) => generators::Generator::FloodFill(generators::FloodFill{mask_path}),
+            Generator::RadialSectors(RadialSectorsCommand{num_sectors, centre_x, centre_y, start_angle, ring_width}) => generators::Generator::RadialSectors(generators::RadialSectors{num_sectors, centre: centre_x.zip(centre_y), start_angle, ring_width}),
+            Generator::Brick(BrickCommand{brick: RectangleTilingCommand{brick_width, brick_height}, offset_ratio}) => generators::Generator::Brick(generators::Brick{brick_width, brick_height, offset_ratio}),
+            Generator::Herringbone(RectangleTilingCommand{brick_width, brick_height}) => generators::Generator::Herringbone(generators::Herringbone{brick_width, brick_height}),
+            Generator::Basketweave(RectangleTilingCommand{brick_width, brick_height}) => generators::Generator::Basketweave(generators::Basketweave{brick_width, brick_height}),
+            Generator::Penrose(PenroseCommand{edge_length, tiling}) => generators::Generator::Penrose(generators::Penrose{edge_length, tiling: tiling.into()}),
+            Generator::TriangleGrid(TilingCommand{edge_length, orientation}) => generators::Generator::TriangleGrid(generators::TriangleGrid{edge_length, orientation: orientation.into()}),
+            Generator::RhombilleGrid(TilingCommand{edge_length, orientation}) => generators::Generator::RhombilleGrid(generators::RhombilleGrid{edge_length, orientation: orientation.into()}),
+            Generator::KisrhombilleGrid(TilingCommand{edge_length, orientation}) => generators::Generator::KisrhombilleGrid(generators::KisrhombilleGrid{edge_length, orientation: orientation.into()})
+        }
+    }
+}
+
+impl From<HexOrientation> for generators::HexOrientation{
+    fn from(orientation: HexOrientation) -> Self{
+        match orientation{
+            HexOrientation::FlatTop => generators::HexOrientation::FlatTop,
+            HexOrientation::PointyTop => generators::HexOrientation::PointyTop
+        }
+    }
+}
+
+impl From<PenroseTiling> for generators::PenroseTiling{
+    fn from(tiling: PenroseTiling) -> Self{
+        match tiling{
+            PenroseTiling::KitesAndDarts => generators::PenroseTiling::KitesAndDarts,
+            PenroseTiling::Rhombs => generators::PenroseTiling::Rhombs
+        }
+    }
+}
+
+impl From<PointDistribution> for generators::PointDistribution{
+    fn from(distribution: PointDistribution) -> Self{
+        match distribution{
+            PointDistribution::Uniform => generators::PointDistribution::Uniform,
+            PointDistribution::PoissonDisc => generators::PointDistribution::PoissonDisc
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,73 @@
+// Colour space conversions. All of these work on floats in [0, 1] (or the natural range of the target space).
+#![allow(clippy::excessive_precision)]     // Matrices are copied verbatim from their references.
+
+#[inline]
+pub fn srgb_to_linear(c: f32) -> f32{
+    if c <= 0.04045{
+        return c / 12.92;
+    }
+    return ((c + 0.055) / 1.055).powf(2.4);
+}
+
+#[inline]
+pub fn linear_to_srgb(c: f32) -> f32{
+    if c <= 0.0031308{
+        return c * 12.92;
+    }
+    return 1.055 * c.powf(1.0 / 2.4) - 0.055;
+}
+
+pub fn linear_to_srgb8(c: f32) -> u8{
+    return (linear_to_srgb(c.clamp(0.0, 1.0)) * 255.0).round() as u8;
+}
+
+// Decoding every pixel with powf is slow, and there are only 256 inputs.
+pub fn srgb8_to_linear_table() -> [f32; 256]{
+    let mut table = [0.0; 256];
+    for (i, entry) in table.iter_mut().enumerate(){
+        *entry = srgb_to_linear(i as f32 / 255.0);
+    }
+    return table;
+}
+
+// OKLab, from https://bottosson.github.io/posts/oklab/
+pub fn linear_rgb_to_oklab(r: f32, g: f32, b: f32) -> (f32, f32, f32){
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+    return (
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s
+    );
+}
+
+pub fn oklab_to_linear_rgb(l: f32, a: f32, b: f32) -> (f32, f32, f32){
+    let l_ = (l + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m_ = (l - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s_ = (l - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+
+    return (
+        4.0767416621 * l_ - 3.3077115913 * m_ + 0.2309699292 * s_,
+        -1.2684380046 * l_ + 2.6097574011 * m_ - 0.3413193965 * s_,
+        -0.0041960863 * l_ - 0.7034186147 * m_ + 1.7076147010 * s_
+    );
+}
+
+// CIELAB with a D65 white point, taking linear RGB.
+pub fn linear_rgb_to_lab(r: f32, g: f32, b: f32) -> (f32, f32, f32){
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+
+    fn f(t: f32) -> f32{
+        if t > 216.0 / 24389.0{
+            return t.cbrt();
+        }
+        return (24389.0 / 27.0 * t + 16.0) / 116.0;
+    }
+
+    let (f_x, f_y, f_z) = (f(x), f(y), f(z));
+    return (116.0 * f_y - 16.0, 500.0 * (f_x - f_y), 200.0 * (f_y - f_z));
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error{
+    // Filesystem problems, with the path that caused them.
+    Io(PathBuf, std::io::Error),
+    // Decoding or encoding failures, with the path that caused them.
+    Image(PathBuf, image::ImageError),
+    // Streaming reads and writes go through png directly, rather than image.
+    PngDecoding(PathBuf, png::DecodingError),
+    PngEncoding(PathBuf, png::EncodingError),
+    NotFound(PathBuf),
+    // Input and output paths that don't make sense together, e.g. a folder input with a file output.
+    PathMismatch(String),
+    DimensionMismatch{stencil: (u32, u32), image: (u32, u32)},
+    BufferSize{width: u32, height: u32, len: usize},
+    InvalidMask(String),
+    InvalidStencil(PathBuf, String),
+    MissingSourceImage,
+    // Some files in a folder failed. Each one has already been reported.
+    BatchFailed{failed: usize, total: usize},
+    ThreadPool(rayon::ThreadPoolBuildError),
+    // Parameters that can't make a stencil at all, e.g. a zero sized grid.
+    InvalidArgument(String),
+    // Valid options that a particular pipeline can't honour.
+    Unsupported(String)
+}
+
+impl fmt::Display for Error{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            Error::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Image(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::PngDecoding(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::PngEncoding(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::NotFound(path) => write!(f, "{} doesn't exist", path.display()),
+            Error::PathMismatch(message) => write!(f, "{}", message),
+            Error::DimensionMismatch{stencil, image} => write!(f, "Stencil dims ({}, {}) != image dims ({}, {})", stencil.0, stencil.1, image.0, image.1),
+            Error::BufferSize{width, height, len} => write!(f, "A {}x{} image can't be made from {} bytes", width, height, len),
+            Error::InvalidMask(message) => write!(f, "Invalid mask: {}", message),
+            Error::InvalidStencil(path, message) => write!(f, "{}: invalid stencil, {}", path.display(), message),
+            Error::MissingSourceImage => write!(f, "This generator needs a source image, try the `dynamic` command"),
+            Error::BatchFailed{failed, total} => write!(f, "{} of {} files failed", failed, total),
+            Error::ThreadPool(e) => write!(f, "Couldn't start the thread pool: {}", e),
+            Error::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            Error::Unsupported(message) => write!(f, "Unsupported: {}", message)
+        }
+    }
+}
+
+impl std::error::Error for Error{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self{
+            Error::Io(_, e) => Some(e),
+            Error::Image(_, e) => Some(e),
+            Error::PngDecoding(_, e) => Some(e),
+            Error::PngEncoding(_, e) => Some(e),
+            Error::ThreadPool(e) => Some(e),
+            _ => None
+        }
+    }
+}
@@ -1,61 +1,662 @@
 use core::fmt;
+use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::path::PathBuf;
 
-use itertools::Itertools;
+use rand::{Rng, SeedableRng, rngs::StdRng};
 
-use crate::{RawImage, args, save_raw_image, image_tools, stenciler::{rgb_to_index}};
+use crate::{colour, image_tools, image_tools::RawImage, stencil_file::{self, Stencil}, stenciler};
+use crate::error::{Error, Result};
 
 pub const BYTES_PER_PIXEL: u32 = 4;         // Wgpu doesn't support 24 bit colours.
+pub const MAX_RGB_INDEX: u32 = 256 * 256 * 256 - 1;    // Stencil images keep their index in the R, G and B bytes.
+
+// Generator parameters
+#[derive(Debug, Clone)]
+pub enum Generator{
+    SquareGrid(SquareGrid),
+    CircleGrid(CircleGrid),
+    ConcentricCircleGrid(ConcentricCircleGrid),
+    CrossGrid(CrossGrid),
+    HexGrid(HexGrid),
+    Voronoi(Voronoi),
+    Slic(Slic),
+    MaskGrid(MaskGrid),
+    FloodFill(FloodFill),
+    RadialSectors(RadialSectors),
+    TriangleGrid(TriangleGrid),
+    RhombilleGrid(RhombilleGrid),
+    KisrhombilleGrid(KisrhombilleGrid),
+    Brick(Brick),
+    Herringbone(Herringbone),
+    Basketweave(Basketweave),
+    Penrose(Penrose),
+    Transformed(Transformed)
+}
+
+#[derive(Debug, Clone)]
+pub struct SquareGrid{
+    pub side_length: u32
+}
+
+#[derive(Debug, Clone)]
+pub struct CircleGrid{
+    pub radius: u32
+}
+
+#[derive(Debug, Clone)]
+pub struct ConcentricCircleGrid{
+    pub radius: u32
+}
+
+#[derive(Debug, Clone)]
+pub struct CrossGrid{
+    pub cross_intersection_width: u32
+}
+
+// Hexagons with the given circumradius
+#[derive(Debug, Clone)]
+pub struct HexGrid{
+    pub radius: u32,
+    pub orientation: HexOrientation
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HexOrientation{
+    FlatTop,
+    PointyTop
+}
+
+// Triangles with the given side length
+#[derive(Debug, Clone)]
+pub struct TriangleGrid{
+    pub edge_length: u32,
+    pub orientation: HexOrientation
+}
+
+// Each hexagon split into three rhombi with the given side length, which looks like stacked cubes.
+#[derive(Debug, Clone)]
+pub struct RhombilleGrid{
+    pub edge_length: u32,
+    pub orientation: HexOrientation
+}
+
+// Each hexagon split into twelve 30-60-90 triangles. `edge_length` is the hypotenuse, the hexagon's circumradius.
+#[derive(Debug, Clone)]
+pub struct KisrhombilleGrid{
+    pub edge_length: u32,
+    pub orientation: HexOrientation
+}
+
+// Running bond: rows of bricks, each shifted along by `offset_ratio` of a brick from the one above.
+#[derive(Debug, Clone)]
+pub struct Brick{
+    pub brick_width: u32,
+    pub brick_height: u32,
+    pub offset_ratio: f32
+}
+
+// Zigzags of alternating horizontal and vertical bricks. The longer side is always the length.
+#[derive(Debug, Clone)]
+pub struct Herringbone{
+    pub brick_width: u32,
+    pub brick_height: u32
+}
+
+// Square blocks of parallel bricks, alternating between horizontal and vertical. Blocks are as big as the longer side.
+#[derive(Debug, Clone)]
+pub struct Basketweave{
+    pub brick_width: u32,
+    pub brick_height: u32
+}
+
+// Aperiodic Penrose tilings, built by repeatedly substituting Robinson triangles until tile edges are `edge_length` long.
+// The centre of five-fold symmetry is the middle of the canvas.
+#[derive(Debug, Clone)]
+pub struct Penrose{
+    pub edge_length: u32,
+    pub tiling: PenroseTiling
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PenroseTiling{
+    // P2
+    KitesAndDarts,
+    // P3
+    Rhombs
+}
+
+// Cells around `num_points` random seeds. A `seed` of None picks a random one.
+#[derive(Debug, Clone)]
+pub struct Voronoi{
+    pub num_points: u32,
+    pub distribution: PointDistribution,
+    pub seed: Option<u64>
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PointDistribution{
+    Uniform,
+    PoissonDisc
+}
+
+// Superpixels following the source image. Needs a source image to generate.
+#[derive(Debug, Clone)]
+pub struct Slic{
+    pub num_segments: u32,
+    pub compactness: f32
+}
+
+// Which neighbours count as touching: edges only, or edges and corners.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connectivity{
+    Four,
+    Eight
+}
+
+#[derive(Debug, Clone)]
+pub struct MaskGrid{
+    pub mask_folder: PathBuf
+}
+
+#[derive(Debug, Clone)]
+pub struct FloodFill{
+    pub mask_path: PathBuf
+}
+
+// Wedges around a centre point, optionally cut into rings as well. A `centre` of None uses the middle of the canvas.
+#[derive(Debug, Clone)]
+pub struct RadialSectors{
+    pub num_sectors: u32,
+    pub centre: Option<(f32, f32)>,
+    // Degrees clockwise from the positive x axis
+    pub start_angle: f32,
+    pub ring_width: Option<u32>
+}
+
+// Another generator, sampled through a geometric transform.
+// Mask based generators are sampled at the nearest pixel and repeat past the canvas. SLIC follows the source image, so it can't be transformed.
+#[derive(Debug, Clone)]
+pub struct Transformed{
+    pub generator: Box<Generator>,
+    pub transform: Transform
+}
+
+// Applied about the centre of the canvas, in this order: mirror, scale, rotate, then offset.
+#[derive(Debug, Clone, Copy)]
+pub struct Transform{
+    // Degrees clockwise
+    pub rotation: f32,
+    pub scale: f32,
+    pub offset: (f32, f32),
+    pub mirror_x: bool,
+    pub mirror_y: bool
+}
+
+impl Default for Transform{
+    fn default() -> Self{
+        Transform{rotation: 0.0, scale: 1.0, offset: (0.0, 0.0), mirror_x: false, mirror_y: false}
+    }
+}
+
+impl Transform{
+    pub fn is_identity(&self) -> bool{
+        return self.rotation % 360.0 == 0.0 && self.scale == 1.0 && self.offset == (0.0, 0.0) && !self.mirror_x && !self.mirror_y;
+    }
+
+    // Maps a point on the generator's canvas to where it ends up on the output canvas.
+    pub fn apply(&self, centre: (f32, f32), point: (f32, f32)) -> (f32, f32){
+        let (x, y) = (point.0 - centre.0, point.1 - centre.1);
+        let x = if self.mirror_x { -x } else { x };
+        let y = if self.mirror_y { -y } else { y };
+        let (sin, cos) = (self.rotation % 360.0).to_radians().sin_cos();
+        let (x, y) = ((x * cos - y * sin) * self.scale, (x * sin + y * cos) * self.scale);
+        return (x + centre.0 + self.offset.0, y + centre.1 + self.offset.1);
+    }
+
+    // Maps a point on the output canvas back to where it came from on the generator's canvas.
+    pub fn invert(&self, centre: (f32, f32), point: (f32, f32)) -> (f32, f32){
+        let (x, y) = (point.0 - centre.0 - self.offset.0, point.1 - centre.1 - self.offset.1);
+        // Whole turns are skipped exactly, so an identity transform doesn't move anything.
+        let (sin, cos) = (-(self.rotation % 360.0).to_radians()).sin_cos();
+        let (x, y) = ((x * cos - y * sin) / self.scale, (x * sin + y * cos) / self.scale);
+        let x = if self.mirror_x { -x } else { x };
+        let y = if self.mirror_y { -y } else { y };
+        return (x + centre.0, y + centre.1);
+    }
+}
 
 // Commands
-pub fn generate_and_save_stencil(width: u32, height: u32, out_path: PathBuf, generator: args::Generator){
-    let buffer = generate_stencil(width, height, &generator);
-    save_raw_image(buffer, out_path);
-}
-
-pub fn generate_stencil(width: u32, height: u32, generator: &args::Generator) -> RawImage{
-    RawImage{
-        width,
-        height,
-        data: match generator{
-            args::Generator::SquareGrid(args::SquareGridCommand{side_length: s}) => generate_square_grid(width, height, *s, 0),
-            args::Generator::CircleGrid(args::CircleGridCommand{radius: r}) => generate_circle_grid(width, height, *r),
-            args::Generator::ConcentricCircleGrid(args::ConcentricCircleGridCommand{radius: r}) => generate_concentric_circle_grid(width, height, *r),
-            args::Generator::CrossGrid(args::CrossGridCommand{cross_intersection_width}) => generate_cross_grid(width, height, *cross_intersection_width),
-            args::Generator::MaskGrid(args::MaskGridCommand{mask_folder}) => generate_from_masks(width, height, mask_folder),
-            args::Generator::FloodFill(args::FloodFillCommand{mask_path}) => generate_fill_bucket(mask_path.to_owned())
+// `preview` also saves a colourised copy there, with `preview_seed` picking the colours.
+pub fn generate_and_save_stencil(width: u32, height: u32, out_path: PathBuf, generator: Generator, compress: bool, preview: Option<PathBuf>, preview_seed: Option<u64>) -> Result<()>{
+    // Generating can take a while, so find out about a bad output format first.
+    stencil_file::check_stencil_path(&out_path)?;
+    let buffer = generate_stencil(width, height, &generator, None)?;
+    if let Some(preview_path) = preview{
+        image_tools::save_raw_image(stenciler::preview_stencil(&buffer, preview_seed), preview_path)?;
+    }
+    return stencil_file::save_stencil(buffer, out_path, compress);
+}
 
-        },
+// Content aware generators need the image being stencilled, so their stencils can't be shared between images.
+pub fn uses_source_image(generator: &Generator) -> bool{
+    return match generator{
+        Generator::Slic(_) => true,
+        Generator::Transformed(Transformed{generator, ..}) => uses_source_image(generator),
+        _ => false
+    };
+}
+
+// Generators whose segments can be in several places on purpose, like the background between circles.
+fn has_disjoint_segments(generator: &Generator) -> bool{
+    return match generator{
+        Generator::CircleGrid(_) | Generator::MaskGrid(_) | Generator::FloodFill(_) => true,
+        Generator::Transformed(Transformed{generator, ..}) => has_disjoint_segments(generator),
+        _ => false
+    };
+}
+
+pub fn generate_stencil(width: u32, height: u32, generator: &Generator, source: Option<&RawImage>) -> Result<Stencil>{
+    if width == 0 || height == 0{
+        return Err(Error::InvalidArgument(format!("can't generate a {}x{} stencil", width, height)));
     }
+    check_parameters(generator)?;
+
+    if uses_source_image(generator){
+        match source{
+            Some(im) if im.width != width || im.height != height => return Err(Error::DimensionMismatch{stencil: (width, height), image: (im.width, im.height)}),
+            None => return Err(Error::MissingSourceImage),
+            _ => {}
+        }
+    }
+
+    let labels = match generator{
+        Generator::Slic(Slic{num_segments, compactness}) => generate_slic(source.unwrap(), *num_segments, *compactness),
+        _ => {
+            let mut labels = render_segments(width, height, &segment_at(width, height, generator, ((0.0, 0.0), (width as f32, height as f32)))?)?;
+            if !has_disjoint_segments(generator){
+                absorb_stray_pieces(width, height, &mut labels);
+            }
+            labels
+        }
+    };
+    return Ok(Stencil{width, height, labels});
+}
+
+// Catches parameters that would divide by zero or never finish, since library callers don't go through clap's checks.
+fn check_parameters(generator: &Generator) -> Result<()>{
+    let positive = |name: &str, value: u32| if value == 0 { Err(Error::InvalidArgument(format!("{} must be at least 1", name))) } else { Ok(()) };
+    let finite = |name: &str, value: f32| if value.is_finite() { Ok(()) } else { Err(Error::InvalidArgument(format!("{} must be a finite number, not {}", name, value))) };
+
+    return match generator{
+        Generator::SquareGrid(SquareGrid{side_length}) => positive("side length", *side_length),
+        Generator::CircleGrid(CircleGrid{radius}) | Generator::ConcentricCircleGrid(ConcentricCircleGrid{radius}) => positive("radius", *radius),
+        Generator::CrossGrid(CrossGrid{cross_intersection_width}) => positive("cross intersection width", *cross_intersection_width),
+        Generator::HexGrid(HexGrid{radius, ..}) => positive("radius", *radius),
+        Generator::Voronoi(Voronoi{num_points, ..}) => positive("number of points", *num_points),
+        Generator::Slic(Slic{num_segments, compactness}) => {
+            positive("number of segments", *num_segments)?;
+            finite("compactness", *compactness)
+        },
+        Generator::MaskGrid(_) | Generator::FloodFill(_) => Ok(()),
+        Generator::RadialSectors(RadialSectors{num_sectors, centre, start_angle, ring_width}) => {
+            positive("number of sectors", *num_sectors)?;
+            if let Some((x, y)) = centre{
+                finite("centre x", *x)?;
+                finite("centre y", *y)?;
+            }
+            if let Some(ring_width) = ring_width{
+                positive("ring width", *ring_width)?;
+            }
+            finite("start angle", *start_angle)
+        },
+        Generator::TriangleGrid(TriangleGrid{edge_length, ..}) | Generator::RhombilleGrid(RhombilleGrid{edge_length, ..}) | Generator::KisrhombilleGrid(KisrhombilleGrid{edge_length, ..}) => positive("edge length", *edge_length),
+        Generator::Brick(Brick{brick_width, brick_height, offset_ratio}) => {
+            positive("brick width", *brick_width)?;
+            positive("brick height", *brick_height)?;
+            finite("offset ratio", *offset_ratio)
+        },
+        Generator::Herringbone(Herringbone{brick_width, brick_height}) | Generator::Basketweave(Basketweave{brick_width, brick_height}) => {
+            positive("brick width", *brick_width)?;
+            positive("brick height", *brick_height)
+        },
+        Generator::Penrose(Penrose{edge_length, ..}) => positive("edge length", *edge_length),
+        Generator::Transformed(Transformed{generator, transform}) => {
+            finite("rotation", transform.rotation)?;
+            finite("x offset", transform.offset.0)?;
+            finite("y offset", transform.offset.1)?;
+            if !(transform.scale.is_finite() && transform.scale > 0.0){
+                return Err(Error::InvalidArgument(format!("scale must be a positive number, not {}", transform.scale)));
+            }
+            check_parameters(generator)
+        }
+    };
 }
 
 // Utility Functions
-fn segment_index_to_rgb(ind: u32) -> (u8, u8, u8){
+/// Packs a segment index into the R, G and B bytes of a stencil pixel, least significant byte first.
+/// Only indices up to `MAX_RGB_INDEX` fit. This is the inverse of `stenciler::rgb_to_index`.
+pub fn segment_index_to_rgb(ind: u32) -> (u8, u8, u8){
     return ((ind % 256) as u8, ((ind / 256) % 256) as u8, (ind / 65536) as u8);
 }
 
 // Generators
-fn generate_square_grid(width: u32, height: u32, side_length: u32, start_at: u32) -> Vec<u8>{
-    let mut container = vec![0 as u8; (width as usize)*(height as usize)*BYTES_PER_PIXEL as usize];
-
-    let squares_per_row = num::Integer::div_ceil(&width, &side_length);
-
-    let mut container_ind: u32 = 0;
+// Every generator but SLIC can say which segment any point is in, so the same lookup draws the plain stencil and samples it through transforms.
+
+// Names the segment a point is in. Points in the same segment always get the same key.
+type SegmentKey = (i64, i64, i64);
+type SegmentAt<'a> = Box<dyn Fn(f32, f32) -> SegmentKey + 'a>;
+// A rectangle, as its top left and bottom right corners.
+type Region = ((f32, f32), (f32, f32));
+
+// `region` bounds every point that will be looked up, which can reach past the canvas under a transform.
+fn segment_at(width: u32, height: u32, generator: &Generator, region: Region) -> Result<SegmentAt<'_>>{
+    let lookup: SegmentAt = match generator{
+        Generator::SquareGrid(SquareGrid{side_length}) => {
+            let side_length = *side_length as f32;
+            Box::new(move |x, y| ((x / side_length).floor() as i64, (y / side_length).floor() as i64, 0))
+        },
+        Generator::CircleGrid(CircleGrid{radius}) => {
+            let circle = generate_circle_stencil(*radius);
+            let period = circle.len() as i64;
+            Box::new(move |x, y| {
+                let (p_x, p_y) = (x.floor() as i64, y.floor() as i64);
+                // Everything between the circles is one background segment.
+                if !circle[p_y.rem_euclid(period) as usize][p_x.rem_euclid(period) as usize]{
+                    return (0, 0, 1);
+                }
+                return (p_x.div_euclid(period), p_y.div_euclid(period), 0);
+            })
+        },
+        Generator::ConcentricCircleGrid(ConcentricCircleGrid{radius}) => {
+            let (centre_x, centre_y) = (width as f32 / 2.0, height as f32 / 2.0);
+            let radius = *radius as f32;
+            // The rings are measured from pixel corners rather than centres.
+            Box::new(move |x, y| ((((x - 0.5 - centre_x).powi(2) + (y - 0.5 - centre_y).powi(2)).sqrt() / radius).floor() as i64, 0, 0))
+        },
+        Generator::CrossGrid(CrossGrid{cross_intersection_width}) => {
+            let cell_width = *cross_intersection_width as f32;
+            Box::new(move |x, y| {
+                let (cell_x, cell_y) = ((x / cell_width).floor() as i64, (y / cell_width).floor() as i64);
+                // Crosses are centred on the cells where 2x + y is a multiple of 5, and every other cell is an arm of one of them.
+                let (arm_x, arm_y) = match (2 * cell_x + cell_y).rem_euclid(5){
+                    0 => (0, 0),
+                    1 => (0, -1),
+                    2 => (-1, 0),
+                    3 => (1, 0),
+                    _ => (0, 1)
+                };
+                return (cell_x + arm_x, cell_y + arm_y, 0);
+            })
+        },
+        Generator::HexGrid(HexGrid{radius, orientation}) => {
+            let (radius, orientation) = (*radius as f32, *orientation);
+            Box::new(move |x, y| {
+                let (major, minor) = oriented(orientation, x, y);
+                let (col, row) = flat_hex_at(major, minor, radius);
+                return (col as i64, row as i64, 0);
+            })
+        },
+        Generator::Voronoi(Voronoi{num_points, distribution, seed}) => {
+            // The seeds stay on the generator's canvas, wherever the transform looks.
+            let seeds = SeedGrid::new(width, height, voronoi_points(width, height, *num_points, *distribution, *seed));
+            Box::new(move |x, y| (seeds.nearest(x, y) as i64, 0, 0))
+        },
+        Generator::RadialSectors(radial_sectors) => {
+            let (centre_x, centre_y) = radial_sectors.centre.unwrap_or((width as f32 / 2.0, height as f32 / 2.0));
+            Box::new(move |x, y| {
+                let (sector, ring) = radial_sector_at(x - centre_x, y - centre_y, radial_sectors);
+                return (sector as i64, ring as i64, 0);
+            })
+        },
+        Generator::TriangleGrid(TriangleGrid{edge_length, orientation}) => {
+            let (edge_length, orientation) = (*edge_length as f32, *orientation);
+            Box::new(move |x, y| {
+                let (major, minor) = oriented(orientation, x, y);
+                let (cell_s, cell_t, upper) = triangle_at(major, minor, edge_length);
+                return (cell_s as i64, cell_t as i64, upper as i64);
+            })
+        },
+        Generator::RhombilleGrid(RhombilleGrid{edge_length, orientation}) | Generator::KisrhombilleGrid(KisrhombilleGrid{edge_length, orientation}) => {
+            let (radius, orientation) = (*edge_length as f32, *orientation);
+            let pieces = if matches!(generator, Generator::RhombilleGrid(_)) { 3 } else { 12 };
+            Box::new(move |x, y| {
+                let (major, minor) = oriented(orientation, x, y);
+                let (col, row, piece) = split_hex_at(major, minor, radius, pieces);
+                return (col as i64, row as i64, piece as i64);
+            })
+        },
+        Generator::Brick(Brick{brick_width, brick_height, offset_ratio}) => {
+            let (brick_width, brick_height, offset_ratio) = (*brick_width as i64, *brick_height as i64, *offset_ratio);
+            Box::new(move |x, y| {
+                let (p_x, p_y) = (x.floor() as i64, y.floor() as i64);
+                let row = p_y.div_euclid(brick_height);
+                let shift = ((row as f32 * offset_ratio).rem_euclid(1.0) * brick_width as f32) as i64;
+                return ((p_x + shift).div_euclid(brick_width), row, 0);
+            })
+        },
+        Generator::Herringbone(Herringbone{brick_width, brick_height}) => {
+            let long = (*brick_width).max(*brick_height) as f32;
+            let short = (*brick_width).min(*brick_height) as f32;
+            Box::new(move |x, y| {
+                let (along, across, horizontal) = herringbone_brick_at(x, y, long, short);
+                return (along, across, horizontal as i64);
+            })
+        },
+        Generator::Basketweave(Basketweave{brick_width, brick_height}) => {
+            let block = (*brick_width).max(*brick_height) as i64;
+            let short = (*brick_width).min(*brick_height) as i64;
+            Box::new(move |x, y| {
+                let (p_x, p_y) = (x.floor() as i64, y.floor() as i64);
+                let (block_x, block_y) = (p_x.div_euclid(block), p_y.div_euclid(block));
+                let brick = if (block_x + block_y).rem_euclid(2) == 0 { p_y.rem_euclid(block) / short } else { p_x.rem_euclid(block) / short };
+                return (block_x, block_y, brick);
+            })
+        },
+        Generator::Penrose(Penrose{edge_length, tiling}) => Box::new(penrose_segment_at(width, height, *edge_length, *tiling, region)?),
+        Generator::MaskGrid(MaskGrid{mask_folder}) => raster_segment_at(width, height, generate_from_masks(width, height, mask_folder)?),
+        Generator::FloodFill(FloodFill{mask_path}) => raster_segment_at(width, height, generate_fill_bucket(width, height, mask_path.to_owned())?),
+        Generator::Transformed(Transformed{generator, transform}) => {
+            let centre = (width as f32 / 2.0, height as f32 / 2.0);
+            // The transform is affine, so the region's corners bound where it looks on the inner canvas.
+            let ((min_x, min_y), (max_x, max_y)) = region;
+            let corners = [(min_x, min_y), (max_x, min_y), (min_x, max_y), (max_x, max_y)].map(|corner| transform.invert(centre, corner));
+            let inner_region = (
+                (corners.iter().map(|c| c.0).fold(f32::MAX, f32::min), corners.iter().map(|c| c.1).fold(f32::MAX, f32::min)),
+                (corners.iter().map(|c| c.0).fold(f32::MIN, f32::max), corners.iter().map(|c| c.1).fold(f32::MIN, f32::max))
+            );
+
+            let inner = segment_at(width, height, generator, inner_region)?;
+            let transform = *transform;
+            Box::new(move |x, y| {
+                let (x, y) = transform.invert(centre, (x, y));
+                return inner(x, y);
+            })
+        },
+        Generator::Slic(_) => return Err(Error::Unsupported("generators that follow the source image can't be transformed".to_string()))
+    };
+    return Ok(lookup);
+}
 
-    // I think a bounds check should be faster than a division. Need to check later.
+// Looks up every pixel centre, numbering the segments in the order they're found so every index is used.
+fn render_segments(width: u32, height: u32, segment_at: &dyn Fn(f32, f32) -> SegmentKey) -> Result<Vec<u32>>{
+    let mut segment_indices: HashMap<SegmentKey, u32> = HashMap::new();
+    let mut labels = Vec::with_capacity((width as usize) * (height as usize));
     for y in 0..height{
+        // Neighbouring pixels are usually in the same segment, so skip the hashing when they are.
+        let mut last: Option<(SegmentKey, u32)> = None;
         for x in 0..width{
-            let square_x = x/side_length;
-            let square_y = y/side_length;
+            let key = segment_at(x as f32 + 0.5, y as f32 + 0.5);
+            let segment_index = match last{
+                Some((last_key, last_index)) if last_key == key => last_index,
+                _ => {
+                    let next_index = segment_indices.len() as u32;
+                    *segment_indices.entry(key).or_insert(next_index)
+                }
+            };
+            last = Some((key, segment_index));
+            labels.push(segment_index);
+        }
+    }
+
+    if segment_indices.len() as u64 > u32::MAX as u64 + 1{
+        return Err(Error::Unsupported(format!("this stencil has more than {} segments", u32::MAX as u64 + 1)));
+    }
+    return Ok(labels);
+}
+
+// Sampling at pixel centres can catch the thin tip of a tile without the pixels between joining it to the rest.
+// Every 4-connected piece but a segment's largest goes to the neighbouring segment it shares the most edge with, so each tile ends up in one piece.
+fn absorb_stray_pieces(width: u32, height: u32, labels: &mut [u32]){
+    let (width, height) = (width as usize, height as usize);
+    let neighbours = |pixel: usize| {
+        let (x, y) = (pixel % width, pixel / width);
+        return [
+            (x > 0).then(|| pixel - 1),
+            (x + 1 < width).then(|| pixel + 1),
+            (y > 0).then(|| pixel - width),
+            (y + 1 < height).then(|| pixel + width)
+        ].into_iter().flatten();
+    };
+
+    let mut piece_of = vec![u32::MAX; labels.len()];
+    let mut piece_areas: Vec<u32> = vec![];
+    let mut to_fill: Vec<usize> = vec![];
+    for start in 0..labels.len(){
+        if piece_of[start] != u32::MAX{
+            continue;
+        }
+        let piece = piece_areas.len() as u32;
+        piece_of[start] = piece;
+        to_fill.push(start);
+        let mut area = 0;
+        while let Some(pixel) = to_fill.pop(){
+            area += 1;
+            for neighbour in neighbours(pixel){
+                if piece_of[neighbour] == u32::MAX && labels[neighbour] == labels[start]{
+                    piece_of[neighbour] = piece;
+                    to_fill.push(neighbour);
+                }
+            }
+        }
+        piece_areas.push(area);
+    }
+
+    // Render numbers the segments densely, so there's a piece for every label.
+    let num_segments = labels.iter().max().map_or(0, |&max| max as usize + 1);
+    if piece_areas.len() == num_segments{
+        return;
+    }
+    let mut main_pieces = vec![u32::MAX; num_segments];
+    for (pixel, &label) in labels.iter().enumerate(){
+        let (piece, main) = (piece_of[pixel], &mut main_pieces[label as usize]);
+        if *main == u32::MAX || piece_areas[piece as usize] > piece_areas[*main as usize]{
+            *main = piece;
+        }
+    }
 
-            let segment_index = square_x + square_y * squares_per_row + start_at;
-            container_ind = fill_pixel_with_segindex(&mut container, container_ind, segment_index);
+    let mut shared_edges: HashMap<(u32, u32), u32> = HashMap::new();
+    for (pixel, &label) in labels.iter().enumerate(){
+        let piece = piece_of[pixel];
+        if main_pieces[label as usize] == piece{
+            continue;
+        }
+        for neighbour in neighbours(pixel){
+            let neighbour_label = labels[neighbour];
+            if main_pieces[neighbour_label as usize] == piece_of[neighbour]{
+                *shared_edges.entry((piece, neighbour_label)).or_insert(0) += 1;
+            }
         }
     }
 
-    return container;
+    // Ties go to the lower index, so the result doesn't depend on the hash order.
+    let mut new_labels: HashMap<u32, (u32, u32)> = HashMap::new();
+    for ((piece, neighbour_label), edges) in shared_edges{
+        let best = new_labels.entry(piece).or_insert((edges, neighbour_label));
+        if (edges, Reverse(neighbour_label)) > (best.0, Reverse(best.1)){
+            *best = (edges, neighbour_label);
+        }
+    }
+    for (pixel, label) in labels.iter_mut().enumerate(){
+        if let Some(&(_, new_label)) = new_labels.get(&piece_of[pixel]){
+            *label = new_label;
+        }
+    }
+}
+
+// Pointy-top tilings are just flat-top ones with x and y swapped.
+fn oriented(orientation: HexOrientation, x: f32, y: f32) -> (f32, f32){
+    return match orientation{
+        HexOrientation::FlatTop => (x, y),
+        HexOrientation::PointyTop => (y, x)
+    };
+}
+
+// Stencils read from masks are looked up at the nearest pixel.
+// Past the canvas they repeat, with each copy's segments kept apart so none of them end up in two places.
+fn raster_segment_at(width: u32, height: u32, labels: Vec<u32>) -> SegmentAt<'static>{
+    return Box::new(move |x, y| {
+        let (p_x, p_y) = (x.floor() as i64, y.floor() as i64);
+        let pixel = x_y_to_index(width, p_x.rem_euclid(width as i64) as u32, p_y.rem_euclid(height as i64) as u32);
+        let copy = (p_x.div_euclid(width as i64), p_y.div_euclid(height as i64));
+        return (labels[pixel] as i64, copy.0, copy.1);
+    });
 }
 
+// Penrose tiles can't be found from a point alone, so generate the triangles covering the region and bucket them into a grid to look points up in.
+fn penrose_segment_at(width: u32, height: u32, edge_length: u32, tiling: PenroseTiling, region: Region) -> Result<impl Fn(f32, f32) -> SegmentKey>{
+    let ((min_x, min_y), (max_x, max_y)) = region;
+    let (min, max) = ((min_x as f64, min_y as f64), (max_x as f64, max_y as f64));
+
+    // Shrinking the tiling far enough would need many more tiles than there are pixels to show them.
+    let f_edge = edge_length as f64;
+    let max_cells = 4.0 * width as f64 * height as f64 + 1_000_000.0;
+    let cells = ((max.0 - min.0) / f_edge + 1.0) * ((max.1 - min.1) / f_edge + 1.0);
+    if cells > max_cells{
+        return Err(Error::Unsupported(format!("the transform shrinks the Penrose tiles too far, try an edge length of at least {}", (edge_length as f64 * (cells / max_cells).sqrt()).ceil())));
+    }
+
+    let centre = (width as f64 / 2.0, height as f64 / 2.0);
+    let triangles = penrose_triangles(centre, (min, max), edge_length, tiling);
+
+    let mut tile_indices: HashMap<PenroseTileKey, u32> = HashMap::new();
+    let tiles: Vec<u32> = triangles.iter().map(|triangle| {
+        let next_index = tile_indices.len() as u32;
+        return *tile_indices.entry(penrose_tile_key(triangle, tiling)).or_insert(next_index);
+    }).collect();
+
+    // Every triangle is smaller than an edge across, so it only lands in a few cells.
+    let cells_x = ((max.0 - min.0) / f_edge).floor() as i64 + 1;
+    let cells_y = ((max.1 - min.1) / f_edge).floor() as i64 + 1;
+    let cell_of = move |x: f64, y: f64| (((x - min.0) / f_edge).floor() as i64, ((y - min.1) / f_edge).floor() as i64);
+    let mut buckets: Vec<Vec<u32>> = vec![vec![]; (cells_x * cells_y) as usize];
+    for (triangle_index, triangle) in triangles.iter().enumerate(){
+        let xs = [triangle.a.0, triangle.b.0, triangle.c.0];
+        let ys = [triangle.a.1, triangle.b.1, triangle.c.1];
+        let (from_x, from_y) = cell_of(xs.iter().cloned().fold(f64::MAX, f64::min), ys.iter().cloned().fold(f64::MAX, f64::min));
+        let (to_x, to_y) = cell_of(xs.iter().cloned().fold(f64::MIN, f64::max), ys.iter().cloned().fold(f64::MIN, f64::max));
+        for (cell_x, cell_y) in itertools::iproduct!(from_x.max(0)..=to_x.min(cells_x - 1), from_y.max(0)..=to_y.min(cells_y - 1)){
+            buckets[(cell_x + cell_y * cells_x) as usize].push(triangle_index as u32);
+        }
+    }
+
+    return Ok(move |x: f32, y: f32| {
+        let point = (x as f64, y as f64);
+        let (cell_x, cell_y) = cell_of(point.0, point.1);
+        let bucket = &buckets[(cell_x.clamp(0, cells_x - 1) + cell_y.clamp(0, cells_y - 1) * cells_x) as usize];
+        // Later triangles win on shared edges.
+        return match bucket.iter().rev().find(|&&triangle_index| {
+            let triangle = &triangles[triangle_index as usize];
+            return triangle_contains([triangle.a, triangle.b, triangle.c], point);
+        }){
+            Some(&triangle_index) => (tiles[triangle_index as usize] as i64, 0, 0),
+            // Float error can leave a point just outside every triangle.
+            None => (-1, 0, 0)
+        };
+    });
+}
+
+#[allow(unused)]
 struct BoolStencilPrinter<'a>{
     stencil: &'a Vec<Vec<bool>>
 }
@@ -63,231 +664,398 @@ struct BoolStencilPrinter<'a>{
 impl fmt::Display for BoolStencilPrinter<'_>{
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for line in self.stencil{
-            if let Err(x) = writeln!(f, "{}\n", line.iter().map(|b| if *b {'.'} else {','}).collect::<String>()) {
-                return Err(x);
-            }
+            writeln!(f, "{}\n", line.iter().map(|b| if *b {'.'} else {','}).collect::<String>())?;
         }
         return Ok(());
     }
 }
 
-fn generate_circle_grid(width: u32, height: u32, radius: u32) -> Vec<u8>{
-    // First let's generate a circle of size circle_size in a temporary vector, then copy it repeatedly into our output.
-    // Note: With this algorithm a circle of radius n actually takes up 2n+1 pixels in width.
-    fn generate_circle_stencil(radius: u32) -> Vec<Vec<bool>>{
-        let mut ret_val = vec![vec![false; (radius*2+1) as usize]; (radius*2+1) as usize];
+// A single circle, which repeats across the canvas.
+// Note: With this algorithm a circle of radius n actually takes up 2n+1 pixels in width.
+fn generate_circle_stencil(radius: u32) -> Vec<Vec<bool>>{
+    let mut ret_val = vec![vec![false; (radius*2+1) as usize]; (radius*2+1) as usize];
 
-        let f_radius = radius as f32;
-        let f_r_2 = f_radius * f_radius;
-        
-        #[derive(Clone, Copy)]
-        struct UintFloatPositionPair{
-            u_x: u32,
-            u_y: u32,
-            f_x: f32,
-            f_y: f32,
+    let f_radius = radius as f32;
+    let f_r_2 = f_radius * f_radius;
+    
+    #[derive(Clone, Copy)]
+    struct UintFloatPositionPair{
+        u_x: u32,
+        u_y: u32,
+        f_x: f32,
+        f_y: f32,
+    }
+
+    fn pair_from_floats(x: f32, y: f32) -> UintFloatPositionPair{
+        UintFloatPositionPair { u_x: x as u32, u_y: y as u32, f_x: x, f_y: y }
+    }
+
+    // Turns out we don't need the horizontal path... TODO: Remove this and slightly refactor
+    fn draw_line_between(start: u32, end: u32, x: u32, v: &mut [Vec<bool>]){
+        if start == end{
+            v[start as usize][x as usize] = true;
+            return;
         }
 
-        fn pair_from_floats(x: f32, y: f32) -> UintFloatPositionPair{
-            UintFloatPositionPair { u_x: x as u32, u_y: y as u32, f_x: x, f_y: y }
+        let dir = if start < end {1} else {-1};
+        let mut cur = start;
+
+        v[start as usize][x as usize] = true;
+        while cur != end{
+            cur = cur.checked_add_signed(dir).expect("This shouldn't overflow...");
+            v[cur as usize][x as usize] = true;
         }
+    }
 
-        // Turns out we don't need the horizontal path... TODO: Remove this and slightly refactor
-        fn draw_line_between(start: u32, end: u32, x: u32, v: &mut Vec<Vec<bool>>) -> (){
-            if start == end{
-                v[start as usize][x as usize] = true;
-                return;
-            }
+    let mut cur_pos = pair_from_floats(f_radius, 0 as f32);        // Starting at the top
 
-            let dir = if start < end {1} else {-1};
-            let mut cur = start;
+    fn fill_circle_at(cur_pos: UintFloatPositionPair, radius: u32, v: &mut [Vec<bool>]){
+        draw_line_between(cur_pos.u_y, 2*radius - cur_pos.u_y, cur_pos.u_x, v);      // mid right
+        draw_line_between(cur_pos.u_y, 2*radius - cur_pos.u_y, 2*radius - cur_pos.u_x, v);  // mid left
+        draw_line_between(cur_pos.u_x, 2*radius - cur_pos.u_x, cur_pos.u_y, v);  // far left
+        draw_line_between(cur_pos.u_x, 2*radius - cur_pos.u_x, 2*radius - cur_pos.u_y, v);  // far right
+    }
 
-            v[start as usize][x as usize] = true;
-            while cur != end{
-                cur = cur.checked_add_signed(dir).expect("This shouldn't overflow...");
-                v[cur as usize][x as usize] = true;
-            }
+    loop{
+        // First, let's fill in the vertical lines from our existing position
+        fill_circle_at(cur_pos, radius, &mut ret_val);
+
+        // Now let's move counterclockwise.
+        let right_square_dist = (cur_pos.f_x - f_radius + 1.0).powi(2);
+        if right_square_dist + (cur_pos.f_y - f_radius).powi(2) <= f_r_2{
+            cur_pos = pair_from_floats(cur_pos.f_x + 1.0, cur_pos.f_y)
+        }
+        else if right_square_dist + (cur_pos.f_y - f_radius + 1.0).powi(2) <= f_r_2{
+            cur_pos = pair_from_floats(cur_pos.f_x + 1.0, cur_pos.f_y + 1.0)
+        }
+        else{
+            cur_pos = pair_from_floats(cur_pos.f_x, cur_pos.f_y + 1.0)
         }
 
-        let mut cur_pos = pair_from_floats(f_radius, 0 as f32);        // Starting at the top
+        if (cur_pos.u_x - radius) < cur_pos.u_y {        // 45deg angle
+            //fill_circle_at(cur_pos, usize_radius, &mut ret_val);
+            break;
+        }
+    }
+
+    return ret_val;
+}
 
-        fn fill_circle_at(cur_pos: UintFloatPositionPair, radius: u32, v: &mut Vec<Vec<bool>>){
-            draw_line_between(cur_pos.u_y, 2*radius - cur_pos.u_y, cur_pos.u_x, v);      // mid right
-            draw_line_between(cur_pos.u_y, 2*radius - cur_pos.u_y, 2*radius - cur_pos.u_x, v);  // mid left
-            draw_line_between(cur_pos.u_x, 2*radius - cur_pos.u_x, cur_pos.u_y, v);  // far left
-            draw_line_between(cur_pos.u_x, 2*radius - cur_pos.u_x, 2*radius - cur_pos.u_y, v);  // far right
+fn voronoi_points(width: u32, height: u32, num_points: u32, distribution: PointDistribution, seed: Option<u64>) -> Vec<(f32, f32)>{
+    let mut rng = match seed{
+        Some(s) => StdRng::seed_from_u64(s),
+        None => StdRng::from_entropy()
+    };
+
+    return match distribution{
+        PointDistribution::Uniform => (0..num_points).map(|_| (rng.gen_range(0.0..width as f32), rng.gen_range(0.0..height as f32))).collect(),
+        PointDistribution::PoissonDisc => {
+            // A maximal poisson-disc sampling with minimum distance r holds roughly 0.7 * area / r^2 points.
+            let min_dist = (0.7 * (width as f32) * (height as f32) / (num_points as f32)).sqrt();
+            poisson_disc_points(width, height, min_dist, &mut rng)
         }
+    };
+}
 
+// Voronoi seeds bucketed into a coarse grid, so we only have to check nearby points to find the closest.
+struct SeedGrid{
+    points: Vec<(f32, f32)>,
+    cell_size: f32,
+    cells_x: i32,
+    cells_y: i32,
+    cells: Vec<Vec<u32>>
+}
+
+impl SeedGrid{
+    fn new(width: u32, height: u32, points: Vec<(f32, f32)>) -> Self{
+        let cell_size = ((width as f32) * (height as f32) / (points.len() as f32)).sqrt().max(1.0);
+        let cells_x = (width as f32 / cell_size).ceil() as i32;
+        let cells_y = (height as f32 / cell_size).ceil() as i32;
+
+        let mut cells: Vec<Vec<u32>> = vec![vec![]; (cells_x * cells_y) as usize];
+        for (point_index, (p_x, p_y)) in points.iter().enumerate(){
+            let cell_x = ((p_x / cell_size) as i32).min(cells_x - 1);
+            let cell_y = ((p_y / cell_size) as i32).min(cells_y - 1);
+            cells[(cell_x + cell_y * cells_x) as usize].push(point_index as u32);
+        }
+
+        return SeedGrid{points, cell_size, cells_x, cells_y, cells};
+    }
+
+    // The index of the seed closest to (f_x, f_y), which doesn't have to be on the canvas.
+    fn nearest(&self, f_x: f32, f_y: f32) -> u32{
+        // Points off the canvas start from the nearest edge cell. The cells past it are still at least a ring further away.
+        let cell_x = ((f_x / self.cell_size).floor() as i32).clamp(0, self.cells_x - 1);
+        let cell_y = ((f_y / self.cell_size).floor() as i32).clamp(0, self.cells_y - 1);
+
+        let mut best_index = 0;
+        let mut best_dist = f32::INFINITY;
+
+        // Search outwards ring by ring. Anything in ring k+1 is at least k cells away, so we can stop once we've beaten that.
+        let mut ring: i32 = 0;
         loop{
-            // First, let's fill in the vertical lines from our existing position
-            fill_circle_at(cur_pos, radius, &mut ret_val);
+            for (c_x, c_y) in itertools::iproduct!((cell_x - ring)..=(cell_x + ring), (cell_y - ring)..=(cell_y + ring)){
+                let on_ring = (c_x - cell_x).abs() == ring || (c_y - cell_y).abs() == ring;
+                if !on_ring || c_x < 0 || c_y < 0 || c_x >= self.cells_x || c_y >= self.cells_y{
+                    continue;
+                }
 
-            // Now let's move counterclockwise.
-            let right_square_dist = (cur_pos.f_x - f_radius + 1.0).powi(2);
-            if right_square_dist + (cur_pos.f_y - f_radius).powi(2) <= f_r_2{
-                cur_pos = pair_from_floats(cur_pos.f_x + 1.0, cur_pos.f_y)
-            }
-            else if right_square_dist + (cur_pos.f_y - f_radius + 1.0).powi(2) <= f_r_2{
-                cur_pos = pair_from_floats(cur_pos.f_x + 1.0, cur_pos.f_y + 1.0)
-            }
-            else{
-                cur_pos = pair_from_floats(cur_pos.f_x, cur_pos.f_y + 1.0)
+                for point_index in &self.cells[(c_x + c_y * self.cells_x) as usize]{
+                    let (p_x, p_y) = self.points[*point_index as usize];
+                    let dist = (p_x - f_x).powi(2) + (p_y - f_y).powi(2);
+                    if dist < best_dist{
+                        best_dist = dist;
+                        best_index = *point_index;
+                    }
+                }
             }
 
-            if (cur_pos.u_x - radius) < cur_pos.u_y {        // 45deg angle
-                //fill_circle_at(cur_pos, usize_radius, &mut ret_val);
+            let covered_dist = ring as f32 * self.cell_size;
+            if best_dist <= covered_dist * covered_dist || ring > self.cells_x.max(self.cells_y){
                 break;
             }
+            ring += 1;
         }
 
-        return ret_val;
+        return best_index;
     }
+}
 
-    let circle = generate_circle_stencil(radius);
-
-    // Now let's copy this stencil all over the image.
-    let mut squares = generate_square_grid(width, height, circle.len() as u32, 1);
-    let circle_mask = repeat_stencil_to_mask(circle, width, height);
+fn poisson_disc_points(width: u32, height: u32, min_dist: f32, rng: &mut StdRng) -> Vec<(f32, f32)>{
+    // Bridson's algorithm. Background cells are small enough to hold at most one point each.
+    const ATTEMPTS_PER_POINT: u32 = 30;
 
-    mask_container(circle_mask, (0, 0, 0), &mut squares).expect("Error masking");
-    return squares
-}
+    let (f_width, f_height) = (width as f32, height as f32);
+    let cell_size = min_dist / 2.0_f32.sqrt();
+    let cells_x = (f_width / cell_size).ceil() as i32;
+    let cells_y = (f_height / cell_size).ceil() as i32;
+    let mut cells: Vec<Option<u32>> = vec![None; (cells_x * cells_y) as usize];
 
-fn generate_cross_grid(width: u32, height: u32, cross_intersection_width: u32) -> Vec<u8>{
-    let mut container = vec![0 as u8; (width as usize) * (height as usize) * BYTES_PER_PIXEL as usize];
-    let grid_width = num::Integer::div_ceil(&width, &cross_intersection_width);
-    let grid_height = num::Integer::div_ceil(&height, &cross_intersection_width);
+    let cell_of = |p: (f32, f32)| (((p.0 / cell_size) as i32).min(cells_x - 1), ((p.1 / cell_size) as i32).min(cells_y - 1));
 
-    let cell_in_grid = |cell_x: i32, cell_y: i32|{
-        return (cell_x >= 0 && (cell_x as u32) < grid_width) && (cell_y >= 0 && (cell_y as u32) < grid_height);
-    };
+    let mut points: Vec<(f32, f32)> = vec![];
+    let mut active: Vec<u32> = vec![];
 
-    let mut fill_cell = |cell_x: i32, cell_y: i32, index: u32| {
-        if !cell_in_grid(cell_x, cell_y){
-            return;
-        }
+    let first = (rng.gen_range(0.0..f_width), rng.gen_range(0.0..f_height));
+    let (first_x, first_y) = cell_of(first);
+    cells[(first_x + first_y * cells_x) as usize] = Some(0);
+    points.push(first);
+    active.push(0);
 
-        let u_cell_x = cell_x as u32;
-        let u_cell_y = cell_y as u32;
+    while !active.is_empty(){
+        let active_index = rng.gen_range(0..active.len());
+        let centre = points[active[active_index] as usize];
+        let mut found = false;
 
-        if u_cell_x >= grid_width || u_cell_y >= grid_height{
-            return;
-        }
+        for _ in 0..ATTEMPTS_PER_POINT{
+            let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+            let dist = rng.gen_range(min_dist..2.0 * min_dist);
+            let candidate = (centre.0 + dist * angle.cos(), centre.1 + dist * angle.sin());
 
-        let start_x = u_cell_x * cross_intersection_width;
-        let start_y = u_cell_y * cross_intersection_width;
+            if candidate.0 < 0.0 || candidate.1 < 0.0 || candidate.0 >= f_width || candidate.1 >= f_height{
+                continue;
+            }
 
-        for x in 0..cross_intersection_width{
-            for y in 0..cross_intersection_width{
-                let cur_x = start_x + x;
-                if cur_x >= width{
-                    continue;
+            let (cand_x, cand_y) = cell_of(candidate);
+            let too_close = itertools::iproduct!((cand_x - 2)..=(cand_x + 2), (cand_y - 2)..=(cand_y + 2)).any(|(c_x, c_y)| {
+                if c_x < 0 || c_y < 0 || c_x >= cells_x || c_y >= cells_y{
+                    return false;
                 }
-
-                let cur_y = start_y + y;
-                if cur_y >= height{
-                    continue;
+                match cells[(c_x + c_y * cells_x) as usize]{
+                    Some(other) => {
+                        let other = points[other as usize];
+                        (other.0 - candidate.0).powi(2) + (other.1 - candidate.1).powi(2) < min_dist * min_dist
+                    },
+                    None => false
                 }
+            });
 
-                fill_pixel_with_segindex(&mut container, (cur_x + width * cur_y) * BYTES_PER_PIXEL, index);
+            if !too_close{
+                cells[(cand_x + cand_y * cells_x) as usize] = Some(points.len() as u32);
+                active.push(points.len() as u32);
+                points.push(candidate);
+                found = true;
+                break;
             }
         }
-    };
 
-    let mut fill_cross = |cell_x: i32, cell_y: i32, index: u32|{
-        fill_cell(cell_x - 1, cell_y, index);
-        fill_cell(cell_x + 1, cell_y, index);
-        fill_cell(cell_x, cell_y -1, index);
-        fill_cell(cell_x, cell_y + 1, index);
-        fill_cell(cell_x, cell_y, index);
-    };
+        if !found{
+            active.swap_remove(active_index);
+        }
+    }
 
-    let cross_touching_grid = |cell_x: i32, cell_y: i32|{
-        return cell_in_grid(cell_x - 1, cell_y) || cell_in_grid(cell_x + 1, cell_y) || cell_in_grid(cell_x, cell_y - 1) || cell_in_grid(cell_x, cell_y + 1) || cell_in_grid(cell_x, cell_y);
-    };
+    return points;
+}
 
-    let mut fill_diag = |start_cell_x: i32, start_cell_y: i32, start_index: u32|{
-        // Assumes that the starting position is in the grid.
-        let mut index = start_index;
-        let mut cell_x = start_cell_x;
-        let mut cell_y = start_cell_y;
+fn generate_slic(source: &RawImage, num_segments: u32, compactness: f32) -> Vec<u32>{
+    // Simple linear iterative clustering (Achanta et al. 2012) on the source image, in CIELAB.
+    const ITERATIONS: u32 = 10;
 
+    let width = source.width as i32;
+    let height = source.height as i32;
+    let num_pixels = (width as usize) * (height as usize);
 
-        while cross_touching_grid(cell_x, cell_y){
-            fill_cross(cell_x, cell_y, index);
-            index += 1;
-            cell_x += 2;
-            cell_y += 1;
-        }
+    // Transparent pixels are composited over white, so empty canvas reads as paper.
+    let lab: Vec<(f32, f32, f32)> = source.data.chunks(BYTES_PER_PIXEL as usize).map(|p| {
+        let alpha = p[3] as f32 / 255.0;
+        let channel = |c: u8| colour::srgb_to_linear((c as f32 / 255.0) * alpha + (1.0 - alpha));
+        colour::linear_rgb_to_lab(channel(p[0]), channel(p[1]), channel(p[2]))
+    }).collect();
 
-        return index;
+    let step = ((num_pixels as f32 / num_segments as f32).sqrt().round() as i32).max(1);
+    let spatial_weight = (compactness / step as f32).powi(2);
+
+    let lab_at = |x: i32, y: i32| lab[(x + y * width) as usize];
+    let gradient_at = |x: i32, y: i32| {
+        if x < 1 || y < 1 || x >= width - 1 || y >= height - 1{
+            return f32::INFINITY;
+        }
+        let lab_dist = |a: (f32, f32, f32), b: (f32, f32, f32)| (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2) + (a.2 - b.2).powi(2);
+        return lab_dist(lab_at(x + 1, y), lab_at(x - 1, y)) + lab_dist(lab_at(x, y + 1), lab_at(x, y - 1));
     };
 
-    // Let's start with the top row:
-    let mut index = 0;
-    let mut cell_x = 0;
-    let mut cell_y = 0;
-    
-    loop{
-        index = fill_diag(cell_x, cell_y, index);
+    // Seed cluster centres on a regular grid, nudged to the lowest gradient in their 3x3 neighbourhood so they don't start on an edge.
+    // Centres are (l, a, b, x, y)
+    let mut centres: Vec<[f32; 5]> = vec![];
+    let mut y = step / 2;
+    while y < height{
+        let mut x = step / 2;
+        while x < width{
+            let (best_x, best_y) = itertools::iproduct!((x - 1)..=(x + 1), (y - 1)..=(y + 1))
+                .filter(|(n_x, n_y)| *n_x >= 0 && *n_y >= 0 && *n_x < width && *n_y < height)
+                .min_by(|a, b| gradient_at(a.0, a.1).total_cmp(&gradient_at(b.0, b.1)))
+                .unwrap();
+            let (l, a, b) = lab_at(best_x, best_y);
+            centres.push([l, a, b, best_x as f32, best_y as f32]);
+            x += step;
+        }
+        y += step;
+    }
+
+    let mut labels = vec![0u32; num_pixels];
+    let mut distances = vec![f32::INFINITY; num_pixels];
+
+    for _ in 0..ITERATIONS{
+        distances.fill(f32::INFINITY);
+
+        for (centre_index, centre) in centres.iter().enumerate(){
+            let (c_x, c_y) = (centre[3].round() as i32, centre[4].round() as i32);
 
-        if cell_in_grid(cell_x, cell_y){
-            cell_x += 3;
-            cell_y -= 1;
+            for p_y in (c_y - step).max(0)..(c_y + step).min(height){
+                for p_x in (c_x - step).max(0)..(c_x + step).min(width){
+                    let (l, a, b) = lab_at(p_x, p_y);
+                    let colour_dist = (l - centre[0]).powi(2) + (a - centre[1]).powi(2) + (b - centre[2]).powi(2);
+                    let spatial_dist = (p_x as f32 - centre[3]).powi(2) + (p_y as f32 - centre[4]).powi(2);
+                    let dist = colour_dist + spatial_dist * spatial_weight;
+
+                    let pixel_index = (p_x + p_y * width) as usize;
+                    if dist < distances[pixel_index]{
+                        distances[pixel_index] = dist;
+                        labels[pixel_index] = centre_index as u32;
+                    }
+                }
+            }
         }
-        else if cell_in_grid(cell_x, cell_y + 1){
-            cell_x += 2;
-            cell_y += 1;
+
+        // Move each centre to the mean of its pixels
+        let mut sums = vec![[0.0f64; 5]; centres.len()];
+        let mut counts = vec![0u32; centres.len()];
+        for (pixel_index, label) in labels.iter().enumerate(){
+            let (l, a, b) = lab[pixel_index];
+            let sum = &mut sums[*label as usize];
+            sum[0] += l as f64;
+            sum[1] += a as f64;
+            sum[2] += b as f64;
+            sum[3] += (pixel_index % width as usize) as f64;
+            sum[4] += (pixel_index / width as usize) as f64;
+            counts[*label as usize] += 1;
         }
-        else{
-            break;
+
+        for (centre, (sum, count)) in centres.iter_mut().zip(sums.iter().zip(counts)){
+            if count != 0{
+                for i in 0..5{
+                    centre[i] = (sum[i] / count as f64) as f32;
+                }
+            }
         }
     }
 
-    // Now let's loop through the left column:
-    cell_x = -1;
-    cell_y = 2;
-    loop{
-        index = fill_diag(cell_x, cell_y, index);
+    // Clusters aren't guaranteed to be connected, so relabel by connected component and fold tiny fragments into a neighbour.
+    let min_component_size = ((step * step) / 4) as usize;
+    let mut new_labels: Vec<Option<u32>> = vec![None; num_pixels];
+    let mut next_label: u32 = 0;
+    let mut component: Vec<usize> = vec![];
 
-        if cell_in_grid(cell_x, cell_y){
-            cell_x -= 1;
-            cell_y += 2;
+    for start in 0..num_pixels{
+        if new_labels[start].is_some(){
+            continue;
         }
-        else if cell_in_grid(cell_x + 1, cell_y){
-            cell_x += 1;
-            cell_y += 3;
+
+        let (start_x, start_y) = ((start % width as usize) as i32, (start / width as usize) as i32);
+        let neighbours = |x: i32, y: i32| [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)].into_iter().filter(|(n_x, n_y)| *n_x >= 0 && *n_y >= 0 && *n_x < width && *n_y < height);
+
+        // Any already labelled neighbour of the first pixel is a candidate to absorb this component.
+        let adjacent_label = neighbours(start_x, start_y).find_map(|(n_x, n_y)| new_labels[(n_x + n_y * width) as usize]);
+
+        component.clear();
+        component.push(start);
+        new_labels[start] = Some(next_label);
+        let mut component_ind = 0;
+
+        while component_ind < component.len(){
+            let pixel_index = component[component_ind];
+            let (p_x, p_y) = ((pixel_index % width as usize) as i32, (pixel_index / width as usize) as i32);
+
+            for (n_x, n_y) in neighbours(p_x, p_y){
+                let neighbour_index = (n_x + n_y * width) as usize;
+                if new_labels[neighbour_index].is_none() && labels[neighbour_index] == labels[start]{
+                    new_labels[neighbour_index] = Some(next_label);
+                    component.push(neighbour_index);
+                }
+            }
+            component_ind += 1;
         }
-        else{
-            break;
+
+        match adjacent_label{
+            Some(adjacent) if component.len() <= min_component_size => {
+                for pixel_index in &component{
+                    new_labels[*pixel_index] = Some(adjacent);
+                }
+            },
+            _ => next_label += 1
         }
     }
 
-    return container;
+    return new_labels.into_iter().map(|label| label.unwrap()).collect();
 }
 
-fn generate_from_masks(width: u32, height: u32, mask_folder_path: &PathBuf) -> Vec<u8>{
+fn generate_from_masks(width: u32, height: u32, mask_folder_path: &PathBuf) -> Result<Vec<u32>>{
     let mut masks: Vec<Vec<Vec<bool>>> = vec![];
 
-    for (_, image) in image_tools::DynamicImageFolderIterator::new(mask_folder_path){
+    for item in image_tools::DynamicImageFolderIterator::new(mask_folder_path)?{
+        let (file_name, image) = item?;
         match image{
             image::DynamicImage::ImageLuma8(greyscale_im) => {
                 if !masks.is_empty() && (greyscale_im.width() as usize != masks[0][0].len() || greyscale_im.height() as usize != masks[0].len()){
-                    panic!("All mask images must be the same size")
+                    return Err(Error::InvalidMask(format!("{:?} isn't the same size as the other masks", file_name)));
                 }
 
                 let mut bool_mask: Vec<Vec<bool>> = vec![vec![false; greyscale_im.width() as usize]; greyscale_im.height() as usize];
 
                 for (x, y, pixel) in greyscale_im.enumerate_pixels(){
-                    bool_mask[y as usize][x as usize] = if pixel.0[0] == 0 { false } else { true };
+                    bool_mask[y as usize][x as usize] = pixel.0[0] != 0;
                 }
 
                 masks.push(bool_mask);
             }
-            _ => panic!("Mask Images should be 8-bit greyscale without alpha")
+            _ => return Err(Error::InvalidMask(format!("{:?} should be 8-bit greyscale without alpha", file_name)))
         }
     }
 
+    if masks.is_empty(){
+        return Err(Error::InvalidMask(format!("No masks found in {}", mask_folder_path.display())));
+    }
+
     let mask_width = masks[0][0].len() as u32;
     let mask_height = masks[0].len() as u32;
     
@@ -302,10 +1070,10 @@ fn generate_from_masks(width: u32, height: u32, mask_folder_path: &PathBuf) -> V
     }
 
     // Now let's tile the masks!
-    let segments_per_row = num::Integer::div_ceil(&width, &mask_width) as u32;
-    let segments_per_mask = (num::Integer::div_ceil(&height, &mask_height) * segments_per_row) as u32;
+    let segments_per_row = num::Integer::div_ceil(&width, &mask_width);
+    let segments_per_mask = num::Integer::div_ceil(&height, &mask_height) * segments_per_row;
 
-    let mut container = vec![0 as u8; (BYTES_PER_PIXEL * width * height) as usize];
+    let mut labels = vec![0u32; (width as usize) * (height as usize)];
 
     for mask in masks{
         let mut pixel_index = 0;
@@ -316,60 +1084,235 @@ fn generate_from_masks(width: u32, height: u32, mask_folder_path: &PathBuf) -> V
                     let segment_x = x/mask_width;
                     let segment_y = y/mask_height;
                     
-                    let segment_index = (start + segment_x + segments_per_row * segment_y);        // TODO: Remove *10
+                    let segment_index = start + segment_x + segments_per_row * segment_y;        // TODO: Remove *10
 
-                    fill_pixel_with_segindex(&mut container, pixel_index, segment_index);
+                    labels[pixel_index] = segment_index;
                 }
-                pixel_index += BYTES_PER_PIXEL;
+                pixel_index += 1;
             }
         }    
         start += segments_per_mask;
     }
 
-    return container;
+    return Ok(labels);
 }
 
-fn generate_concentric_circle_grid(width: u32, height: u32, radius: u32) -> Vec<u8>{
-    let mut ret_vector = vec![0 as u8; (BYTES_PER_PIXEL as usize) * (height as usize) * (width as usize)];
+// Which flat-top hex of circumradius `radius` a point is in, as "odd-q" offset coordinates with hex (0, 0) centred on the origin.
+fn flat_hex_at(major: f32, minor: f32, radius: f32) -> (i32, i32){
+    let sqrt_3 = 3.0_f32.sqrt();
 
-    let mut pixel_start_index: u32 = 0;
-    for y in 0..height{
-        for x in 0..width{
-            // TODO: Rewrite to fill 4 pixels per sqrt or to use circle drawing algorithm.
-            let dist = ((x as f32 - (width as f32/2.0)).powi(2) + (y as f32 - (height as f32/2.0)).powi(2)).sqrt();
+    // Fractional axial coordinates
+    let q = (2.0/3.0 * major) / radius;
+    let r = (-1.0/3.0 * major + sqrt_3/3.0 * minor) / radius;
+    let s = -q - r;
+
+    // Cube rounding: round everything, then fix whichever coordinate moved furthest.
+    let (mut r_q, mut r_r, r_s) = (q.round(), r.round(), s.round());
+    let (d_q, d_r, d_s) = ((r_q - q).abs(), (r_r - r).abs(), (r_s - s).abs());
 
-            let segment_index = (dist/(radius as f32)).floor() as u32;
-            pixel_start_index = fill_pixel_with_segindex(&mut ret_vector, pixel_start_index, segment_index);
+    if d_q > d_r && d_q > d_s{
+        r_q = -r_r - r_s;
+    }
+    else if d_r > d_s{
+        r_r = -r_q - r_s;
+    }
+
+    let col = r_q as i32;
+    let row = r_r as i32 + (col - (col & 1))/2;
+    return (col, row);
+}
+
+fn flat_hex_centre(col: i32, row: i32, radius: f32) -> (f32, f32){
+    return (1.5 * radius * col as f32, 3.0_f32.sqrt() * radius * (row as f32 + 0.5 * (col & 1) as f32));
+}
+
+// Triangles with sides of `edge_length`. Flat-top triangles have a horizontal side, pointy-top ones are turned on their side.
+// Which triangle a point is in, as the cell of the skewed grid and whether it's the upper of the cell's two triangles.
+fn triangle_at(major: f32, minor: f32, edge_length: f32) -> (i32, i32, u32){
+    // Skewed coordinates, where every unit parallelogram is two triangles.
+    let t = minor / (edge_length * 3.0_f32.sqrt() / 2.0);
+    let s = major / edge_length - t / 2.0;
+    let (cell_s, cell_t) = (s.floor(), t.floor());
+    let upper = ((s - cell_s) + (t - cell_t) >= 1.0) as u32;
+    return (cell_s as i32, cell_t as i32, upper);
+}
+
+// Hexes cut from their centre into `pieces` equal wedges, starting from a corner.
+// Three pieces gives rhombille (the stacked cubes illusion), and twelve gives kisrhombille.
+// Which hex a point is in, and which of its `pieces` wedges.
+fn split_hex_at(major: f32, minor: f32, radius: f32, pieces: u32) -> (i32, i32, u32){
+    let (col, row) = flat_hex_at(major, minor, radius);
+    let (centre_major, centre_minor) = flat_hex_centre(col, row, radius);
+    let angle = (minor - centre_minor).atan2(major - centre_major).rem_euclid(std::f32::consts::TAU);
+    let piece = ((angle / (std::f32::consts::TAU / pieces as f32)) as u32).min(pieces - 1);
+    return (col, row, piece);
+}
+
+// One staircase of the pattern is horizontal bricks at (t*short, t*short) with vertical ones hanging below them.
+// Every brick is a lattice translate of the horizontal brick at the origin or the vertical one below it,
+// and staircases repeat along (short, short), with the next staircase along (-long, long).
+// The lattice translate a point is in, and whether it's the horizontal brick of the two.
+fn herringbone_brick_at(p_x: f32, p_y: f32, long: f32, short: f32) -> (i64, i64, bool){
+    let in_horizontal = |x: f32, y: f32| (0.0..long).contains(&x) && (0.0..short).contains(&y);
+    let in_vertical = |x: f32, y: f32| (0.0..short).contains(&x) && (short..short + long).contains(&y);
+
+    // Lattice coordinates of the point, then search the nearby translates for the brick it's in.
+    let along = ((p_x + p_y) / (2.0 * short)).floor() as i64;
+    let across = ((p_y - p_x) / (2.0 * long)).floor() as i64;
+
+    for a in along - 2..=along + 1{
+        for b in across - 1..=across + 1{
+            let local_x = p_x - (a as f32 * short - b as f32 * long);
+            let local_y = p_y - (a as f32 * short + b as f32 * long);
+            if in_horizontal(local_x, local_y) || in_vertical(local_x, local_y){
+                return (a, b, in_horizontal(local_x, local_y));
+            }
+        }
+    }
+
+    return (along, across, true);
+}
+
+// Half of a Penrose tile: either the 36-72-72 triangle (half a kite or thin rhomb) or the 36-36-108 one (half a dart or thick rhomb).
+// For rhombs `a` is the apex and the tile's other half is across `b`-`c`.
+// For kites and darts `a` is the tip, `c` the other end of the tile's axis and the other half is across `a`-`c`.
+#[derive(Clone, Copy)]
+struct RobinsonTriangle{
+    acute: bool,
+    a: (f64, f64),
+    b: (f64, f64),
+    c: (f64, f64)
+}
+
+// Every triangle of the tiling centred on `centre` that touches the `region` rectangle, given as its top left and bottom right corners.
+fn penrose_triangles(centre: (f64, f64), region: ((f64, f64), (f64, f64)), edge_length: u32, tiling: PenroseTiling) -> Vec<RobinsonTriangle>{
+    const GOLDEN_RATIO: f64 = 1.618_033_988_749_895;
+    let lerp = |from: (f64, f64), to: (f64, f64)| (from.0 + (to.0 - from.0) / GOLDEN_RATIO, from.1 + (to.1 - from.1) / GOLDEN_RATIO);
+    let ((min_x, min_y), (max_x, max_y)) = region;
+
+    // Start from a wheel of ten triangles big enough to cover the region, which is a decagon.
+    let furthest = (centre.0 - min_x).abs().max((max_x - centre.0).abs()).hypot((centre.1 - min_y).abs().max((max_y - centre.1).abs()));
+    let needed_radius = (furthest + 1.0) / 18.0_f64.to_radians().cos();
+    let levels = (needed_radius / edge_length as f64).log(GOLDEN_RATIO).ceil().max(0.0) as i32;
+    let radius = edge_length as f64 * GOLDEN_RATIO.powi(levels);
+
+    let corner = |i: i32| {
+        let (sin, cos) = (i as f64 * std::f64::consts::PI / 5.0).sin_cos();
+        return (centre.0 + radius * cos, centre.1 + radius * sin);
+    };
+    let mut triangles: Vec<RobinsonTriangle> = (0..10).map(|i| {
+        let (b, c) = if i % 2 == 0 { (corner(i), corner(i + 1)) } else { (corner(i + 1), corner(i)) };
+        return RobinsonTriangle{acute: true, a: centre, b, c};
+    }).collect();
+
+    let in_region = |t: &RobinsonTriangle| {
+        let xs = [t.a.0, t.b.0, t.c.0];
+        let ys = [t.a.1, t.b.1, t.c.1];
+        return xs.iter().cloned().fold(f64::MAX, f64::min) <= max_x && xs.iter().cloned().fold(f64::MIN, f64::max) >= min_x
+            && ys.iter().cloned().fold(f64::MAX, f64::min) <= max_y && ys.iter().cloned().fold(f64::MIN, f64::max) >= min_y;
+    };
+
+    for _ in 0..levels{
+        let mut next = Vec::with_capacity(triangles.len() * 3);
+        for &RobinsonTriangle{acute, a, b, c} in &triangles{
+            let tri = |acute, a, b, c| RobinsonTriangle{acute, a, b, c};
+            match (tiling, acute){
+                (PenroseTiling::Rhombs, true) => {
+                    let p = lerp(a, b);
+                    next.extend([tri(true, c, p, b), tri(false, p, c, a)]);
+                },
+                (PenroseTiling::Rhombs, false) => {
+                    let q = lerp(b, a);
+                    let r = lerp(b, c);
+                    next.extend([tri(false, r, c, a), tri(false, q, r, b), tri(true, r, q, a)]);
+                },
+                (PenroseTiling::KitesAndDarts, true) => {
+                    let t = lerp(b, a);
+                    let r = lerp(a, c);
+                    next.extend([tri(false, a, r, t), tri(true, b, t, r), tri(true, b, c, r)]);
+                },
+                (PenroseTiling::KitesAndDarts, false) => {
+                    let p = lerp(a, b);
+                    next.extend([tri(true, a, p, c), tri(false, b, c, p)]);
+                }
+            }
         }
+        next.retain(in_region);
+        triangles = next;
     }
 
-    return ret_vector;
+    return triangles;
 }
 
-fn generate_fill_bucket(mask_path: PathBuf) -> Vec<u8>{
-    return fill_bucket_grid(image_tools::get_raw_image(mask_path));
+type PenroseTileKey = (bool, (i64, i64), (i64, i64));
+
+// Both halves of a tile compute their shared edge the same way, so it names the tile once it's rounded.
+fn penrose_tile_key(triangle: &RobinsonTriangle, tiling: PenroseTiling) -> PenroseTileKey{
+    let quantise = |p: (f64, f64)| ((p.0 * 64.0).round() as i64, (p.1 * 64.0).round() as i64);
+    let (from, to) = match tiling{
+        PenroseTiling::Rhombs => (quantise(triangle.b), quantise(triangle.c)),
+        PenroseTiling::KitesAndDarts => (quantise(triangle.a), quantise(triangle.c))
+    };
+    return (triangle.acute, from.min(to), from.max(to));
+}
+
+// Edges count as inside, so neighbouring triangles never leave a gap between them.
+fn triangle_contains(corners: [(f64, f64); 3], point: (f64, f64)) -> bool{
+    let [p0, p1, p2] = corners;
+    let edge = |from: (f64, f64), to: (f64, f64), x: f64, y: f64| (to.0 - from.0) * (y - from.1) - (to.1 - from.1) * (x - from.0);
+    // Normalise the winding, so the inside is where every edge function is positive.
+    let winding = edge(p0, p1, p2.0, p2.1).signum();
+    const EPSILON: f64 = 1e-9;
+
+    let (p_x, p_y) = point;
+    return winding * edge(p0, p1, p_x, p_y) >= -EPSILON && winding * edge(p1, p2, p_x, p_y) >= -EPSILON && winding * edge(p2, p0, p_x, p_y) >= -EPSILON;
 }
 
-fn fill_bucket_grid(input_im: RawImage) -> Vec<u8>{
-    let mut is_filled = vec![false; (input_im.height * input_im.width) as usize];
-    let mut ret_vector = vec![0 as u8; (BYTES_PER_PIXEL as usize) * (input_im.height as usize) * (input_im.width as usize)];
+// The sector and ring of a point (dx, dy) away from the centre.
+fn radial_sector_at(dx: f32, dy: f32, radial_sectors: &RadialSectors) -> (u32, u32){
+    // y points down, so atan2 already goes clockwise.
+    let angle = (dy.atan2(dx) - radial_sectors.start_angle.to_radians()).rem_euclid(std::f32::consts::TAU);
+    let sector = ((angle / (std::f32::consts::TAU / radial_sectors.num_sectors as f32)) as u32).min(radial_sectors.num_sectors - 1);
+
+    let ring = match radial_sectors.ring_width{
+        Some(ring_width) => ((dx * dx + dy * dy).sqrt() / ring_width as f32) as u32,
+        None => 0
+    };
+    return (sector, ring);
+}
+
+
+fn generate_fill_bucket(width: u32, height: u32, mask_path: PathBuf) -> Result<Vec<u32>>{
+    let mask = image_tools::get_raw_image(mask_path)?;
+    if mask.width != width || mask.height != height{
+        return Err(Error::DimensionMismatch{stencil: (width, height), image: (mask.width, mask.height)});
+    }
+    return fill_bucket_grid(&Stencil::from_rgb(&mask), Connectivity::Four);
+}
+
+// Gives every connected region of same-index pixels its own segment.
+pub(crate) fn fill_bucket_grid(input_im: &Stencil, connectivity: Connectivity) -> Result<Vec<u32>>{
+    let mut is_filled = vec![false; (input_im.height as usize) * (input_im.width as usize)];
+    let mut ret_vector = vec![0u32; (input_im.height as usize) * (input_im.width as usize)];
 
     let mut fill_from = |start_x: u32, start_y: u32, colour_ind: u32, is_filled: &mut Vec<bool>|{
         if start_x >= input_im.width || start_y >= input_im.height{
             return;
         }
 
-        let start_im_index = x_y_to_index(input_im.width, start_x, start_y);
-        let start_segment_index = rgb_to_index(input_im.data[start_im_index as usize], input_im.data[start_im_index as usize + 1], input_im.data[start_im_index as usize + 2]);
+        let start_segment_index = input_im.labels[x_y_to_index(input_im.width, start_x, start_y)];
         let mut to_fill: Vec<(u32, u32)> = vec![];
         to_fill.push((start_x, start_y));
 
         let mut inner_lop = |to_fill: &mut Vec<(u32, u32)>, fill_pos: (u32, u32)|{
+            if fill_pos.0 >= input_im.width || fill_pos.1 >= input_im.height{
+                return;
+            }
             let image_index = x_y_to_index(input_im.width, fill_pos.0, fill_pos.1);
 
-            if !is_filled[(fill_pos.0 + input_im.height * fill_pos.1) as usize] && rgb_to_index(input_im.data[image_index as usize], input_im.data[image_index as usize + 1], input_im.data[image_index as usize + 2]) == start_segment_index{
-                fill_pixel_with_segindex(&mut ret_vector, image_index as u32, colour_ind);
-                is_filled[(fill_pos.0 + input_im.height * fill_pos.1) as usize] = true;
+            if !is_filled[image_index] && input_im.labels[image_index] == start_segment_index{
+                ret_vector[image_index] = colour_ind;
+                is_filled[image_index] = true;
 
                 if fill_pos.1 >= 1{
                     to_fill.push((fill_pos.0, fill_pos.1 - 1));
@@ -380,6 +1323,19 @@ fn fill_bucket_grid(input_im: RawImage) -> Vec<u8>{
                     to_fill.push((fill_pos.0 - 1, fill_pos.1));
                 }
                 to_fill.push((fill_pos.0 + 1, fill_pos.1));
+
+                if connectivity == Connectivity::Eight{
+                    if fill_pos.0 >= 1{
+                        to_fill.push((fill_pos.0 - 1, fill_pos.1 + 1));
+                        if fill_pos.1 >= 1{
+                            to_fill.push((fill_pos.0 - 1, fill_pos.1 - 1));
+                        }
+                    }
+                    if fill_pos.1 >= 1{
+                        to_fill.push((fill_pos.0 + 1, fill_pos.1 - 1));
+                    }
+                    to_fill.push((fill_pos.0 + 1, fill_pos.1 + 1));
+                }
             }
         };
 
@@ -391,65 +1347,112 @@ fn fill_bucket_grid(input_im: RawImage) -> Vec<u8>{
     let mut segment_index: u32 = 0;
     for y in 0..input_im.height{
         for x in 0..input_im.width{
-            if !is_filled[(x + input_im.height * y) as usize]{
+            if !is_filled[x_y_to_index(input_im.width, x, y)]{
+                let Some(next_index) = segment_index.checked_add(1) else {
+                    return Err(Error::Unsupported(format!("this stencil has more than {} connected pieces", u32::MAX as u64 + 1)));
+                };
                 fill_from(x, y, segment_index, &mut is_filled);
-                segment_index += 1;    
+                segment_index = next_index;
             }
         }
     }
     
-    return ret_vector;
+    return Ok(ret_vector);
 }
 
 // Utility functions
-#[derive(Debug)]
-enum MaskingError{
-    LengthMismatch
+#[inline]
+fn x_y_to_index(width: u32, x: u32, y: u32) -> usize{
+    return x as usize + y as usize * width as usize;
 }
 
-fn repeat_stencil_to_mask(stencil: Vec<Vec<bool>>, width: u32, height: u32) -> Vec<bool>{
-    let mut mask = vec![false; (width as usize)*(height as usize)];
-
-    let y_len = stencil.len() as u32;
-    let x_len = stencil[0].len() as u32;
-    
-    for y in 0..height{
-        for x in 0..width{
-            mask[(x + y * width) as usize] = stencil[(y % y_len) as usize][(x % x_len) as usize];
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // Every one of these used to panic or never finish.
+    #[test]
+    fn rejects_zero_sizes(){
+        let zero_sized = [
+            Generator::SquareGrid(SquareGrid{side_length: 0}),
+            Generator::CrossGrid(CrossGrid{cross_intersection_width: 0}),
+            Generator::HexGrid(HexGrid{radius: 0, orientation: HexOrientation::FlatTop}),
+            Generator::Voronoi(Voronoi{num_points: 0, distribution: PointDistribution::Uniform, seed: Some(0)}),
+            Generator::RadialSectors(RadialSectors{num_sectors: 0, centre: None, start_angle: 0.0, ring_width: None}),
+            Generator::Brick(Brick{brick_width: 0, brick_height: 4, offset_ratio: 0.5}),
+            Generator::Basketweave(Basketweave{brick_width: 4, brick_height: 0}),
+            Generator::Penrose(Penrose{edge_length: 0, tiling: PenroseTiling::Rhombs}),
+            Generator::Transformed(Transformed{generator: Box::new(Generator::SquareGrid(SquareGrid{side_length: 0})), transform: Transform{rotation: 30.0, ..Transform::default()}})
+        ];
+        for generator in zero_sized{
+            assert!(matches!(generate_stencil(16, 16, &generator, None), Err(Error::InvalidArgument(_))), "{:?}", generator);
         }
+        assert!(matches!(generate_stencil(0, 16, &Generator::SquareGrid(SquareGrid{side_length: 4}), None), Err(Error::InvalidArgument(_))));
     }
-
-    return mask;
-}
-
-fn mask_container(bool_mask: Vec<bool>, false_value: (u8,u8, u8), container: &mut Vec<u8>) -> Result<(), MaskingError>{
-    // Masks `container` s.t. if bool_mask[i] == false, then pixel[i] = false_value;
-    if container.len() != BYTES_PER_PIXEL as usize*bool_mask.len(){
-        return Err(MaskingError::LengthMismatch);
+    // Sampling through a transform shares the plain path's lookups, so doing nothing has to change nothing.
+    #[test]
+    fn identity_transform_matches_plain_stencil(){
+        let mask_path = std::env::temp_dir().join(format!("rip-generators-{}-mask.png", std::process::id()));
+        stencil_file::save_stencil(generate_stencil(61, 47, &Generator::HexGrid(HexGrid{radius: 6, orientation: HexOrientation::FlatTop}), None).unwrap(), mask_path.clone(), false).unwrap();
+
+        let generators = [
+            Generator::SquareGrid(SquareGrid{side_length: 7}),
+            Generator::CircleGrid(CircleGrid{radius: 5}),
+            Generator::ConcentricCircleGrid(ConcentricCircleGrid{radius: 6}),
+            Generator::CrossGrid(CrossGrid{cross_intersection_width: 4}),
+            Generator::HexGrid(HexGrid{radius: 8, orientation: HexOrientation::PointyTop}),
+            Generator::Voronoi(Voronoi{num_points: 30, distribution: PointDistribution::Uniform, seed: Some(3)}),
+            Generator::RadialSectors(RadialSectors{num_sectors: 7, centre: None, start_angle: 10.0, ring_width: Some(9)}),
+            Generator::TriangleGrid(TriangleGrid{edge_length: 9, orientation: HexOrientation::FlatTop}),
+            Generator::RhombilleGrid(RhombilleGrid{edge_length: 9, orientation: HexOrientation::FlatTop}),
+            Generator::KisrhombilleGrid(KisrhombilleGrid{edge_length: 12, orientation: HexOrientation::PointyTop}),
+            Generator::Brick(Brick{brick_width: 11, brick_height: 5, offset_ratio: 0.3}),
+            Generator::Herringbone(Herringbone{brick_width: 12, brick_height: 4}),
+            Generator::Basketweave(Basketweave{brick_width: 12, brick_height: 4}),
+            Generator::Penrose(Penrose{edge_length: 10, tiling: PenroseTiling::KitesAndDarts}),
+            Generator::FloodFill(FloodFill{mask_path: mask_path.clone()})
+        ];
+        for generator in generators{
+            let plain = generate_stencil(61, 47, &generator, None).unwrap();
+            let transformed = generate_stencil(61, 47, &Generator::Transformed(Transformed{generator: Box::new(generator.clone()), transform: Transform::default()}), None).unwrap();
+            assert!(plain.labels == transformed.labels, "{:?}", generator);
+        }
+        std::fs::remove_file(mask_path).unwrap();
     }
-
-    for i in 0..bool_mask.len(){
-        if !bool_mask[i]{
-            container[BYTES_PER_PIXEL as usize*i] = false_value.0;
-            container[BYTES_PER_PIXEL as usize*i + 1] = false_value.1;
-            container[BYTES_PER_PIXEL as usize*i + 2] = false_value.2;
+    // Thin rhomb and triangle tips used to leave stray pixels that only touched their tile at a corner.
+    #[test]
+    fn tiles_come_out_in_one_piece(){
+        let generators = [
+            Generator::KisrhombilleGrid(KisrhombilleGrid{edge_length: 12, orientation: HexOrientation::FlatTop}),
+            Generator::Penrose(Penrose{edge_length: 8, tiling: PenroseTiling::Rhombs}),
+            Generator::Transformed(Transformed{generator: Box::new(Generator::TriangleGrid(TriangleGrid{edge_length: 6, orientation: HexOrientation::PointyTop})), transform: Transform{rotation: 33.0, ..Transform::default()}})
+        ];
+        for generator in generators{
+            let report = crate::stencil_tools::inspect_stencil(&generate_stencil(200, 150, &generator, None).unwrap()).unwrap();
+            assert_eq!(report.unused_indices, 0, "{:?}", generator);
+            assert!(report.segments.iter().all(|segment| segment.components == 1), "{:?}", generator);
         }
     }
-
-    return Ok(());
-}
-
-#[inline]
-fn fill_pixel_with_segindex(container: &mut Vec<u8>, pixel_start_index: u32, segment_index: u32) -> u32{
-    let segment_color = segment_index_to_rgb(segment_index);
-    container[pixel_start_index as usize] = segment_color.0;
-    container[pixel_start_index as usize + 1] = segment_color.1;
-    container[pixel_start_index as usize + 2] = segment_color.2;
-    container[pixel_start_index as usize + 3] = 255;
-    return pixel_start_index + BYTES_PER_PIXEL;
-}
-
-#[inline]
-fn x_y_to_index(width: u32, x: u32, y: u32) -> u32{
-    return x + y * width * BYTES_PER_PIXEL;
+    // Transformed generators sample through `invert`, so it has to land back where `apply` started.
+    #[test]
+    fn invert_undoes_apply(){
+        let transforms = [
+            Transform::default(),
+            Transform{rotation: 33.0, ..Transform::default()},
+            Transform{rotation: -410.0, scale: 2.5, ..Transform::default()},
+            Transform{scale: 0.3, offset: (12.0, -7.5), mirror_x: true, ..Transform::default()},
+            Transform{rotation: 90.0, scale: 1.7, offset: (-3.0, 40.0), mirror_x: true, mirror_y: true}
+        ];
+        let centre = (100.0, 75.0);
+        for transform in transforms{
+            for point in [(0.0, 0.0), (100.0, 75.0), (199.0, 3.0), (-20.5, 160.25)]{
+                let there = transform.apply(centre, point);
+                let back = transform.invert(centre, there);
+                assert!((back.0 - point.0).abs() < 1e-3 && (back.1 - point.1).abs() < 1e-3, "{:?} {:?} -> {:?} -> {:?}", transform, point, there, back);
+            }
+        }
+        // Quarter turns are clockwise, with y pointing down.
+        let turned = Transform{rotation: 90.0, ..Transform::default()}.apply((0.0, 0.0), (1.0, 0.0));
+        assert!(turned.0.abs() < 1e-6 && (turned.1 - 1.0).abs() < 1e-6, "{:?}", turned);
+    }
 }
\ No newline at end of file
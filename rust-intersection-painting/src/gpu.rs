@@ -1,11 +1,13 @@
 // State from https://sotrh.github.io/learn-wgpu/beginner/tutorial2-surface/#first-some-housekeeping-state
 use wgpu::{self, Texture, Extent3d, CommandEncoderDescriptor, TextureUsages, TextureFormat};
+#[allow(unused)]
 struct State {
     device: wgpu::Device,
     queue: wgpu::Queue,
     command_encoder: wgpu::CommandEncoder,
 }
 
+#[allow(unused)]
 impl State {
     // Creating some of the wgpu types requires async code
     async fn new() -> Self {
@@ -36,7 +38,7 @@ impl State {
 
     fn create_2d_texture(&self, width: u32, height: u32) -> Texture{
         return self.device.create_texture(
-            &wgpu::TextureDescriptor { label: None, size: Extent3d { width: width, height: height, depth_or_array_layers: 1 }, mip_level_count: 1, sample_count: 1, dimension: wgpu::TextureDimension::D2, format: TextureFormat::Rgba8Uint, usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST }
+            &wgpu::TextureDescriptor { label: None, size: Extent3d { width, height, depth_or_array_layers: 1 }, mip_level_count: 1, sample_count: 1, dimension: wgpu::TextureDimension::D2, format: TextureFormat::Rgba8Uint, usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST }
         )
     }
 }
\ No newline at end of file
@@ -1,8 +1,11 @@
 use std::path::Path;
 use std::path::PathBuf;
 use std::fs;
+use std::io::{self, Write};
 use image::{io::Reader as ImageReader, DynamicImage};
 
+use crate::error::{Error, Result};
+
 // Decomposes an image into (skip, (width, height), pixel_buffer)
 pub struct RawImage{
     pub width: u32,
@@ -17,61 +20,68 @@ pub fn decompose_image(im: DynamicImage) -> RawImage{
 }
 
 // IO
-fn raw_image_to_rgba(r: RawImage) -> image::ImageBuffer<image::Rgba<u8>, Vec<u8>>{
-    return image::ImageBuffer::from_raw(r.width, r.height, r.data).expect("Container not large enough");
+fn raw_image_to_rgba(r: RawImage) -> Result<image::ImageBuffer<image::Rgba<u8>, Vec<u8>>>{
+    let (width, height, len) = (r.width, r.height, r.data.len());
+    return image::ImageBuffer::from_raw(r.width, r.height, r.data).ok_or(Error::BufferSize{width, height, len});
 }
 
 
-pub fn save_raw_image(r: RawImage, out_path: PathBuf){
-    let image = raw_image_to_rgba(r);
-    image.save(out_path).expect("Image didn't save");    
+pub fn save_raw_image(r: RawImage, out_path: PathBuf) -> Result<()>{
+    let image = raw_image_to_rgba(r)?;
+    return image.save(&out_path).map_err(|e| Error::Image(out_path, e));
 }
 
-pub fn get_raw_image<P: AsRef<Path>>(path: P) -> RawImage{
-    return decompose_image(get_image(path));
+pub fn get_raw_image<P: AsRef<Path>>(path: P) -> Result<RawImage>{
+    return get_image(path).map(decompose_image);
 }
 
-fn get_image<P>(path: P) -> DynamicImage
+fn get_image<P>(path: P) -> Result<DynamicImage>
     where P: AsRef<Path>
 {
-    return ImageReader::open(path).expect("").decode().expect("");  // TODO: Fix these excepts...
+    let path = path.as_ref();
+    let reader = ImageReader::open(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+    return reader.decode().map_err(|e| Error::Image(path.to_path_buf(), e));
 }
 
-fn get_image_from_direntry(dir: &fs::DirEntry) -> DynamicImage{
-    let metadata = dir.metadata();
+fn get_image_from_direntry(dir: &fs::DirEntry) -> Result<DynamicImage>{
+    let metadata = dir.metadata().map_err(|e| Error::Io(dir.path(), e))?;
 
-    match metadata{
-        Ok(m) => {
-            if m.is_file(){
-                return get_image(dir.path());
-            }
-            else{
-                panic!("{:?} is not a file", dir.file_name().to_str());
-            }
-        }
-        Err(e) => panic!("Error reading file metadata {}", e)
+    if !metadata.is_file(){
+        return Err(Error::PathMismatch(format!("{} is not a file", dir.path().display())));
     }
+    return get_image(dir.path());
 }
 
 
+// Lists a folder without decoding anything, so callers can decode in parallel.
+pub fn folder_entries<P: AsRef<Path>>(folder: P) -> Result<Vec<PathBuf>>{
+    let folder = folder.as_ref();
+    let entries = std::fs::read_dir(folder).map_err(|e| Error::Io(folder.to_path_buf(), e))?;
+    return entries.map(|entry| entry.map(|e| e.path()).map_err(|e| Error::Io(folder.to_path_buf(), e))).collect();
+}
+
 // Image Folder Iterators
 pub struct DynamicImageFolderIterator{
+    folder: PathBuf,
     base_iterator: fs::ReadDir
 }
 
 impl DynamicImageFolderIterator{
-    pub fn new<P: AsRef<Path>>(folder: P) -> Self {
-        DynamicImageFolderIterator { base_iterator: std::fs::read_dir(folder).unwrap() }
+    pub fn new<P: AsRef<Path>>(folder: P) -> Result<Self> {
+        let folder = folder.as_ref().to_path_buf();
+        let base_iterator = std::fs::read_dir(&folder).map_err(|e| Error::Io(folder.clone(), e))?;
+        Ok(DynamicImageFolderIterator { folder, base_iterator })
     }
 }
 
+// Failures are per-file, so one bad image doesn't stop the rest of the folder.
 impl Iterator for DynamicImageFolderIterator{
-    type Item = (std::ffi::OsString, DynamicImage);
+    type Item = Result<(std::ffi::OsString, DynamicImage)>;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.base_iterator.next().map(|maybe_dir_entry| {
-            let dir_entry = maybe_dir_entry.unwrap();
-            (dir_entry.file_name(), get_image_from_direntry(&dir_entry))
+            let dir_entry = maybe_dir_entry.map_err(|e| Error::Io(self.folder.clone(), e))?;
+            Ok((dir_entry.file_name(), get_image_from_direntry(&dir_entry)?))
         })
     }
 }
@@ -81,15 +91,100 @@ pub struct RawImageFolderIterator{
 }
 
 impl RawImageFolderIterator{
-    pub fn new<P: AsRef<Path>>(folder: P) -> Self {
-        RawImageFolderIterator { base_iterator: DynamicImageFolderIterator::new(folder) }
+    pub fn new<P: AsRef<Path>>(folder: P) -> Result<Self> {
+        Ok(RawImageFolderIterator { base_iterator: DynamicImageFolderIterator::new(folder)? })
     }
 }
 
 impl Iterator for RawImageFolderIterator{
-    type Item = (std::ffi::OsString, RawImage);
+    type Item = Result<(std::ffi::OsString, RawImage)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.base_iterator.next().map(|(file_name, dynamic_image)| (file_name, decompose_image(dynamic_image)))
+        self.base_iterator.next().map(|item| item.map(|(file_name, dynamic_image)| (file_name, decompose_image(dynamic_image))))
+    }
+}
+
+
+// Strip-at-a-time PNG IO, for images too big to hold in memory.
+// Only PNG is supported, since it's the only format we use that can be decoded row by row.
+pub struct PngStripReader{
+    path: PathBuf,
+    reader: png::Reader<io::BufReader<fs::File>>,
+    rows_read: u32
+}
+
+impl PngStripReader{
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self>{
+        let path = path.as_ref().to_path_buf();
+        let file = fs::File::open(&path).map_err(|e| Error::Io(path.clone(), e))?;
+
+        let mut decoder = png::Decoder::new(io::BufReader::new(file));
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let reader = decoder.read_info().map_err(|e| Error::PngDecoding(path.clone(), e))?;
+
+        if reader.info().interlaced{
+            return Err(Error::Unsupported(format!("{} is interlaced, so it can't be read in strips", path.display())));
+        }
+        return Ok(PngStripReader{path, reader, rows_read: 0});
+    }
+
+    pub fn width(&self) -> u32{
+        return self.reader.info().width;
+    }
+
+    pub fn height(&self) -> u32{
+        return self.reader.info().height;
+    }
+
+    // Replaces `strip` with up to `rows` rows of RGBA8 pixels, returning how many rows were read. 0 means we're done.
+    pub fn read_strip(&mut self, rows: u32, strip: &mut Vec<u8>) -> Result<u32>{
+        strip.clear();
+        let (colour_type, _) = self.reader.output_color_type();
+        let rows = rows.min(self.height() - self.rows_read);
+
+        for _ in 0..rows{
+            let row = self.reader.next_row().map_err(|e| Error::PngDecoding(self.path.clone(), e))?;
+            let row = row.ok_or_else(|| Error::Unsupported(format!("{} ended early", self.path.display())))?;
+            let data = row.data();
+
+            match colour_type{
+                png::ColorType::Rgba => strip.extend_from_slice(data),
+                png::ColorType::Rgb => data.chunks_exact(3).for_each(|p| strip.extend_from_slice(&[p[0], p[1], p[2], 255])),
+                png::ColorType::GrayscaleAlpha => data.chunks_exact(2).for_each(|p| strip.extend_from_slice(&[p[0], p[0], p[0], p[1]])),
+                png::ColorType::Grayscale => data.iter().for_each(|&p| strip.extend_from_slice(&[p, p, p, 255])),
+                // EXPAND turns palettes into RGB(A), so this can't happen.
+                png::ColorType::Indexed => return Err(Error::Unsupported(format!("{} has an unexpanded palette", self.path.display())))
+            }
+        }
+        self.rows_read += rows;
+        return Ok(rows);
+    }
+}
+
+pub struct PngStripWriter{
+    path: PathBuf,
+    writer: png::StreamWriter<'static, io::BufWriter<fs::File>>
+}
+
+impl PngStripWriter{
+    pub fn create<P: AsRef<Path>>(path: P, width: u32, height: u32) -> Result<Self>{
+        let path = path.as_ref().to_path_buf();
+        let file = fs::File::create(&path).map_err(|e| Error::Io(path.clone(), e))?;
+
+        let mut encoder = png::Encoder::new(io::BufWriter::new(file), width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let writer = encoder.write_header().and_then(|w| w.into_stream_writer()).map_err(|e| Error::PngEncoding(path.clone(), e))?;
+
+        return Ok(PngStripWriter{path, writer});
+    }
+
+    // `strip` must be whole rows of RGBA8 pixels.
+    pub fn write_strip(&mut self, strip: &[u8]) -> Result<()>{
+        return self.writer.write_all(strip).map_err(|e| Error::Io(self.path.clone(), e));
+    }
+
+    pub fn finish(self) -> Result<()>{
+        return self.writer.finish().map_err(|e| Error::PngEncoding(self.path, e));
     }
 }
@@ -0,0 +1,15 @@
+#![allow(clippy::needless_return)]
+
+pub mod generators;
+pub mod stenciler;
+pub mod stencil_file;
+pub mod stencil_tools;
+pub mod image_tools;
+pub mod error;
+pub mod streaming;
+mod colour;
+mod gpu;
+
+pub use image_tools::RawImage;
+pub use stencil_file::Stencil;
+pub use error::{Error, Result};
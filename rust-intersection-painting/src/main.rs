@@ -1,95 +1,178 @@
+#![allow(clippy::needless_return)]
+
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use itertools::Itertools;
-use crate::image_tools::{save_raw_image, get_raw_image, RawImage};
+use rust_intersection_painting::{generators, stenciler, stencil_file, stencil_tools, streaming, image_tools, Error, Result};
+use rust_intersection_painting::image_tools::{save_raw_image, get_raw_image, RawImage};
+use rust_intersection_painting::stencil_file::Stencil;
 use clap::Parser;
+use rayon::prelude::*;
 
-mod generators;
 mod args;
-mod stenciler;
-mod image_tools;
-mod gpu;
 
 #[tokio::main]
 async fn main(){
-    println!("Args = {}", std::env::args().into_iter().join(" "));
-    run_cmdline();
+    eprintln!("Args = {}", std::env::args().join(" "));
+    if let Err(e) = run_cmdline(){
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
 }
 
-fn run_cmdline(){
+fn run_cmdline() -> Result<()>{
     let arguments = args::Arguments::parse();
 
     match arguments.command_type{
-        args::GeneratorType::GenerateStencil(args::GenerateStencilCommand{width: w, height: h, output: out, generator: g}) => generators::generate_and_save_stencil(w, h, out, g),
-        args::GeneratorType::Static(args::StaticCommand{stencil: s, alpha_averaging, output: out_path, input: in_path}) => static_command(s, alpha_averaging, in_path, out_path),
-        args::GeneratorType::Dynamic(args::DynamicCommand{input: in_path, alpha_averaging, output: out_path, generator: g}) => dynamic_command(g, alpha_averaging, in_path, out_path)
+        args::GeneratorType::GenerateStencil(args::GenerateStencilCommand{width: w, height: h, output: out, compress, preview, preview_seed, transform, generator: g}) => generators::generate_and_save_stencil(w, h, out, transform.apply(g.into()), compress, preview, preview_seed),
+        args::GeneratorType::Static(args::StaticCommand{stencil: s, painting, output: out_path, input: in_path, stream, strip_rows}) => {
+            if stream{
+                return streaming::streaming_pipeline(s, in_path, out_path, &painting.into(), strip_rows);
+            }
+            static_command(s, painting.into(), in_path, out_path)
+        },
+        args::GeneratorType::Dynamic(args::DynamicCommand{input: in_path, painting, jobs, transform, output: out_path, generator: g}) => dynamic_command(transform.apply(g.into()), painting.into(), jobs, in_path, out_path),
+        args::GeneratorType::PreviewStencil(args::PreviewStencilCommand{stencil, output, seed}) => {
+            let stencil_image = stencil_file::load_stencil(stencil)?;
+            save_raw_image(stenciler::preview_stencil(&stencil_image, seed), output)
+        },
+        args::GeneratorType::InspectStencil(args::InspectStencilCommand{stencil, format}) => {
+            let report = stencil_tools::inspect_stencil(&stencil_file::load_stencil(stencil)?)?;
+            match format{
+                args::ReportFormat::Text => print!("{}", report),
+                args::ReportFormat::Json => println!("{}", report.to_json())
+            }
+            Ok(())
+        },
+        args::GeneratorType::TransformStencil(args::TransformStencilCommand{stencil, output, compress, transform}) => transform_stencil_command(stencil, transform, compress, output),
+        args::GeneratorType::CombineStencils(args::CombineStencilsCommand{first, second, output, compress}) => {
+            stencil_file::check_stencil_path(&output)?;
+            let combined = stencil_tools::combine_stencils(&stencil_file::load_stencil(first)?, &stencil_file::load_stencil(second)?)?;
+            stencil_file::save_stencil(combined, output, compress)
+        }
     }
 }
 
 // Command functions
-fn static_command(stencil: PathBuf, alpha_averaging: bool, in_path: PathBuf, out_path: PathBuf){
-    let stencil_image = get_raw_image(stencil);
-    let input_image = get_raw_image(in_path);
+fn transform_stencil_command(stencil: PathBuf, transform: args::StencilTransform, compress: bool, out_path: PathBuf) -> Result<()>{
+    stencil_file::check_stencil_path(&out_path)?;
+    let stencil_image = stencil_file::load_stencil(stencil)?;
+
+    let transformed = match transform{
+        args::StencilTransform::Relabel => stencil_tools::relabel_stencil(&stencil_image),
+        args::StencilTransform::SplitComponents(args::SplitComponentsCommand{connectivity}) => stencil_tools::split_components(&stencil_image, connectivity.into())?,
+        args::StencilTransform::MergeSmall(args::MergeSmallCommand{min_area, image}) => {
+            let image = image.map(get_raw_image).transpose()?;
+            stencil_tools::merge_small_segments(&stencil_image, min_area, image.as_ref())?
+        }
+    };
+    return stencil_file::save_stencil(transformed, out_path, compress);
+}
+
+fn static_command(stencil: PathBuf, options: stenciler::PipelineOptions, in_path: PathBuf, out_path: PathBuf) -> Result<()>{
+    let stencil_image = stencil_file::load_stencil(stencil)?;
+    let input_image = get_raw_image(in_path)?;
 
-    let out_image = stenciler::cpu_pipeline(&stencil_image, alpha_averaging, &input_image);
-    save_raw_image(out_image, out_path);
+    let out_image = stenciler::cpu_pipeline(&stencil_image, &options, &input_image)?;
+    return save_raw_image(out_image, out_path);
 }
 
 // Command functions
-fn generate_stencil_from_image(im: &RawImage, generator: &args::Generator) -> RawImage{
+fn generate_stencil_from_image(im: &RawImage, generator: &generators::Generator) -> Result<Stencil>{
     let width = im.width;
     let height = im.height;
 
-    return generators::generate_stencil(width, height, generator);
+    return generators::generate_stencil(width, height, generator, Some(im));
 }
 
-fn dynamic_command(generator: args::Generator, alpha_averaging: bool, in_path: PathBuf, out_path: PathBuf){
+// Filled in by whichever file at that resolution gets there first.
+type StencilSlot = Mutex<Option<Arc<Stencil>>>;
+
+fn dynamic_command(generator: generators::Generator, options: stenciler::PipelineOptions, jobs: Option<u32>, in_path: PathBuf, out_path: PathBuf) -> Result<()>{
     if !in_path.exists(){
-        panic!("Input path doesn't exist")
+        return Err(Error::NotFound(in_path));
     }
 
     if in_path.is_file(){
         if out_path.exists() && !out_path.is_file(){
-            panic!("Input is file but output isn't")
+            return Err(Error::PathMismatch("Input is file but output isn't".to_string()));
         }
 
-        let input_image = get_raw_image(in_path);
+        let input_image = get_raw_image(in_path)?;
     
-        let stencil_image = generate_stencil_from_image(&input_image, &generator);
+        let stencil_image = generate_stencil_from_image(&input_image, &generator)?;
     
-        let out_image = stenciler::cpu_pipeline(&stencil_image, alpha_averaging, &input_image);
-        save_raw_image(out_image, out_path);
+        let out_image = stenciler::cpu_pipeline(&stencil_image, &options, &input_image)?;
+        return save_raw_image(out_image, out_path);
     }
     else if in_path.is_dir(){
         if out_path.exists(){
             if !out_path.is_dir(){
-                panic!("Input is folder, but output isn't")
+                return Err(Error::PathMismatch("Input is folder, but output isn't".to_string()));
             }
         }
         else{
-            std::fs::create_dir_all(out_path.to_owned()).expect("Failed to create output directory");
+            std::fs::create_dir_all(&out_path).map_err(|e| Error::Io(out_path.clone(), e))?;
         }
 
-        let mut stencils: HashMap<(u32, u32), image_tools::RawImage> = HashMap::new();     // Resolution to stencil.
+        // Resolution to stencil. Each resolution has its own slot, so one slow stencil doesn't hold up the others,
+        // and generating while holding the slot means each stencil only gets made once.
+        let stencils: Mutex<HashMap<(u32, u32), Arc<StencilSlot>>> = Mutex::new(HashMap::new());
 
-        for (file_name, input_image) in image_tools::RawImageFolderIterator::new(in_path){
+        // A bad file gets reported and skipped, so it doesn't take the rest of the batch down with it.
+        let process_file = |in_file: &PathBuf| -> Result<()>{
+            let input_image = get_raw_image(in_file)?;
+            let out_file = out_path.join(in_file.file_name().unwrap());
             let width = input_image.width;
             let height = input_image.height;
             
-            let stencil_image = if let Some(stencil) = stencils.get(&(width, height)){
-                stencil
-            } else{
-                let stencil_image = generators::generate_stencil(width, height, &generator);
-            
-                stencils.insert((width, height), stencil_image);    
-                stencils.get(&(width, height)).unwrap()
+            // Content aware stencils are unique to each image, so there's nothing to share.
+            if generators::uses_source_image(&generator){
+                let stencil_image = generate_stencil_from_image(&input_image, &generator)?;
+                let out_image = stenciler::cpu_pipeline(&stencil_image, &options, &input_image)?;
+                return save_raw_image(out_image, out_file);
+            }
+
+            let slot = stencils.lock().unwrap().entry((width, height)).or_default().clone();
+            let stencil_image = {
+                let mut slot = slot.lock().unwrap();
+                match slot.as_ref(){
+                    Some(stencil) => stencil.clone(),
+                    None => {
+                        // A failed stencil leaves the slot empty, so the next file at this resolution tries again.
+                        let stencil_image = Arc::new(generators::generate_stencil(width, height, &generator, None)?);
+                        *slot = Some(stencil_image.clone());
+                        stencil_image
+                    }
+                }
             };
             
-            let out_image = stenciler::cpu_pipeline(stencil_image, alpha_averaging, &input_image);
-            save_raw_image(out_image, out_path.join(file_name));
+            let out_image = stenciler::cpu_pipeline(&stencil_image, &options, &input_image)?;
+            return save_raw_image(out_image, out_file);
+        };
+
+        let in_files = image_tools::folder_entries(in_path)?;
+        let total = in_files.len();
+
+        // 0 threads tells rayon to use one per core.
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs.unwrap_or(0) as usize).build().map_err(Error::ThreadPool)?;
+        let failed = pool.install(|| in_files.par_iter().filter(|in_file| {
+            match process_file(in_file){
+                Ok(()) => false,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    true
+                }
+            }
+        }).count());
+
+        if failed != 0{
+            return Err(Error::BatchFailed{failed, total});
         }
+        return Ok(());
     }
     else{
-        panic!("Input should be file or folder.")
+        return Err(Error::PathMismatch("Input should be file or folder.".to_string()));
     }
-}
\ No newline at end of file
+}
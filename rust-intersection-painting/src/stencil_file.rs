@@ -0,0 +1,362 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
+
+use crate::error::{Error, Result};
+use crate::generators::{BYTES_PER_PIXEL, MAX_RGB_INDEX, segment_index_to_rgb};
+use crate::image_tools::{PngStripReader, RawImage, get_raw_image, save_raw_image};
+use crate::stenciler::rgb_to_index;
+
+// Native stencil files store one little endian u32 segment index per pixel, after a fixed size header:
+//   magic (8 bytes) | version (u8) | compression (u8) | reserved (2 bytes) | width (u32) | height (u32)
+// Unlike images, nothing can quietly round or re-encode the indices, and every u32 is a valid index.
+pub const STENCIL_EXTENSION: &str = "stencil";
+
+const MAGIC: &[u8; 8] = b"RIPSTNCL";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compressed{
+    No = 0,
+    Zlib = 1
+}
+
+// A stencil in memory: one segment index per pixel, row by row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stencil{
+    pub width: u32,
+    pub height: u32,
+    pub labels: Vec<u32>
+}
+
+impl Stencil{
+    // Reads each pixel's index out of a stencil image's R, G and B bytes.
+    pub fn from_rgb(image: &RawImage) -> Stencil{
+        let labels = image.data.chunks_exact(BYTES_PER_PIXEL as usize).map(|pixel| rgb_to_index(pixel[0], pixel[1], pixel[2]) as u32).collect();
+        return Stencil{width: image.width, height: image.height, labels};
+    }
+
+    // Stencil images only have room for indices up to MAX_RGB_INDEX, so bigger ones fail rather than wrapping around.
+    pub fn to_rgb(&self) -> Result<RawImage>{
+        let mut data = Vec::with_capacity(self.labels.len() * BYTES_PER_PIXEL as usize);
+        for &label in &self.labels{
+            if label > MAX_RGB_INDEX{
+                return Err(Error::Unsupported(format!("segment index {} doesn't fit in a stencil image, which holds up to {} segments. Use .{} instead", label, MAX_RGB_INDEX as u64 + 1, STENCIL_EXTENSION)));
+            }
+            let (r, g, b) = segment_index_to_rgb(label);
+            data.extend_from_slice(&[r, g, b, 255]);
+        }
+        return Ok(RawImage{width: self.width, height: self.height, data});
+    }
+}
+
+pub fn is_stencil_file<P: AsRef<Path>>(path: P) -> bool{
+    return path.as_ref().extension().is_some_and(|extension| extension.eq_ignore_ascii_case(STENCIL_EXTENSION));
+}
+
+// Reads either a native stencil file or a stencil image.
+pub fn load_stencil<P: AsRef<Path>>(path: P) -> Result<Stencil>{
+    let path = path.as_ref();
+    if !is_stencil_file(path){
+        return Ok(Stencil::from_rgb(&get_raw_image(path)?));
+    }
+
+    let (width, height, labels_len, reader) = open_labels(path)?;
+
+    // Compressed lengths can't be checked up front, so the buffer only grows as fast as the data decompresses.
+    let mut bytes = Vec::new();
+    reader.take(labels_len + 1).read_to_end(&mut bytes).map_err(|e| read_error(path, e))?;
+    if bytes.len() as u64 != labels_len{
+        let problem = if (bytes.len() as u64) < labels_len { "ran out of segment indices" } else { "has more segment indices than pixels" };
+        return Err(invalid(path, problem));
+    }
+
+    let labels = bytes.chunks_exact(4).map(|label| u32::from_le_bytes(label.try_into().unwrap())).collect();
+    return Ok(Stencil{width, height, labels});
+}
+
+// Checks the header, returning the size and a reader positioned at the (decompressed) segment indices.
+fn open_labels(path: &Path) -> Result<(u32, u32, u64, Box<dyn Read>)>{
+    let file = fs::File::open(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+    let mut reader = io::BufReader::new(file);
+
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header).map_err(|e| match e.kind(){
+        io::ErrorKind::UnexpectedEof => invalid(path, "too short for a stencil header"),
+        _ => Error::Io(path.to_path_buf(), e)
+    })?;
+    if &header[0..8] != MAGIC{
+        return Err(invalid(path, "not a stencil file"));
+    }
+    if header[8] != VERSION{
+        return Err(invalid(path, &format!("unknown version {}", header[8])));
+    }
+    let compressed = match header[9]{
+        0 => Compressed::No,
+        1 => Compressed::Zlib,
+        other => return Err(invalid(path, &format!("unknown compression {}", other)))
+    };
+    let width = u32::from_le_bytes(header[12..16].try_into().unwrap());
+    let height = u32::from_le_bytes(header[16..20].try_into().unwrap());
+
+    // Check the header against what's actually there before trusting it with an allocation.
+    let labels_len = (width as u64).checked_mul(height as u64).and_then(|pixels| pixels.checked_mul(4)).filter(|len| *len <= usize::MAX as u64)
+        .ok_or_else(|| invalid(path, &format!("{}x{} is too big", width, height)))?;
+    if compressed == Compressed::No{
+        let file_len = reader.get_ref().metadata().map_err(|e| Error::Io(path.to_path_buf(), e))?.len();
+        if file_len != HEADER_LEN as u64 + labels_len{
+            return Err(invalid(path, &format!("expected {} bytes of segment indices for {}x{}, found {}", labels_len, width, height, file_len.saturating_sub(HEADER_LEN as u64))));
+        }
+    }
+
+    let reader: Box<dyn Read> = match compressed{
+        Compressed::No => Box::new(reader),
+        Compressed::Zlib => Box::new(ZlibDecoder::new(reader))
+    };
+    return Ok((width, height, labels_len, reader));
+}
+
+fn read_error(path: &Path, e: io::Error) -> Error{
+    return match e.kind(){
+        io::ErrorKind::UnexpectedEof => invalid(path, "ran out of segment indices"),
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => invalid(path, &format!("corrupt compressed data, {}", e)),
+        _ => Error::Io(path.to_path_buf(), e)
+    };
+}
+
+// Strip-at-a-time stencil reading for the streaming pipeline, from either a native stencil file or a PNG.
+pub struct StencilStripReader{
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    rows_read: u32,
+    source: StripSource
+}
+
+enum StripSource{
+    Native(Box<dyn Read>),
+    // Keeps the decoded RGBA rows around between strips.
+    Png(Box<PngStripReader>, Vec<u8>)
+}
+
+impl StencilStripReader{
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self>{
+        let path = path.as_ref().to_path_buf();
+        if is_stencil_file(&path){
+            let (width, height, _, reader) = open_labels(&path)?;
+            return Ok(StencilStripReader{path, width, height, rows_read: 0, source: StripSource::Native(reader)});
+        }
+
+        let reader = PngStripReader::open(&path)?;
+        let (width, height) = (reader.width(), reader.height());
+        return Ok(StencilStripReader{path, width, height, rows_read: 0, source: StripSource::Png(Box::new(reader), Vec::new())});
+    }
+
+    pub fn width(&self) -> u32{
+        return self.width;
+    }
+
+    pub fn height(&self) -> u32{
+        return self.height;
+    }
+
+    // Replaces `strip` with up to `rows` rows of segment indices, returning how many rows were read. 0 means we're done.
+    pub fn read_strip(&mut self, rows: u32, strip: &mut Vec<u32>) -> Result<u32>{
+        strip.clear();
+        let rows = rows.min(self.height - self.rows_read);
+
+        match &mut self.source{
+            StripSource::Native(reader) => {
+                let mut bytes = vec![0u8; rows as usize * self.width as usize * 4];
+                reader.read_exact(&mut bytes).map_err(|e| read_error(&self.path, e))?;
+                strip.extend(bytes.chunks_exact(4).map(|label| u32::from_le_bytes(label.try_into().unwrap())));
+
+                // Compressed files can't be checked up front, so make sure nothing's left over once we're done.
+                if rows > 0 && self.rows_read + rows == self.height && reader.read(&mut [0u8]).map_err(|e| read_error(&self.path, e))? != 0{
+                    return Err(invalid(&self.path, "has more segment indices than pixels"));
+                }
+            },
+            StripSource::Png(reader, pixels) => {
+                reader.read_strip(rows, pixels)?;
+                strip.extend(pixels.chunks_exact(BYTES_PER_PIXEL as usize).map(|pixel| rgb_to_index(pixel[0], pixel[1], pixel[2]) as u32));
+            }
+        }
+        self.rows_read += rows;
+        return Ok(rows);
+    }
+}
+
+// Stencils can't go through lossy formats, since a single changed byte moves a pixel into another segment.
+pub fn check_stencil_path<P: AsRef<Path>>(path: P) -> Result<()>{
+    let path = path.as_ref();
+    if is_stencil_file(path){
+        return Ok(());
+    }
+
+    match image::ImageFormat::from_path(path){
+        Ok(image::ImageFormat::Png | image::ImageFormat::Bmp | image::ImageFormat::Tiff | image::ImageFormat::Tga | image::ImageFormat::Farbfeld) => return Ok(()),
+        Ok(format) => return Err(Error::Unsupported(format!("{:?} can't hold a stencil losslessly, use .png or .{}", format, STENCIL_EXTENSION))),
+        Err(e) => return Err(Error::Image(path.to_path_buf(), e))
+    }
+}
+
+// `compress` only applies to native stencil files, images use their format's own compression.
+pub fn save_stencil(stencil: Stencil, out_path: PathBuf, compress: bool) -> Result<()>{
+    check_stencil_path(&out_path)?;
+    if !is_stencil_file(&out_path){
+        return save_raw_image(stencil.to_rgb()?, out_path);
+    }
+
+    let compressed = if compress { Compressed::Zlib } else { Compressed::No };
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&[VERSION, compressed as u8, 0, 0]);
+    header.extend_from_slice(&stencil.width.to_le_bytes());
+    header.extend_from_slice(&stencil.height.to_le_bytes());
+
+    let labels: Vec<u8> = stencil.labels.iter().flat_map(|label| label.to_le_bytes()).collect();
+
+    let file = fs::File::create(&out_path).map_err(|e| Error::Io(out_path.clone(), e))?;
+    let mut writer = io::BufWriter::new(file);
+    let written = writer.write_all(&header).and_then(|_| match compressed{
+        Compressed::No => writer.write_all(&labels),
+        Compressed::Zlib => {
+            let mut encoder = ZlibEncoder::new(&mut writer, Compression::default());
+            encoder.write_all(&labels)?;
+            encoder.finish().map(|_| ())
+        }
+    }).and_then(|_| writer.flush());
+
+    return written.map_err(|e| Error::Io(out_path, e));
+}
+
+fn invalid(path: &Path, message: &str) -> Error{
+    return Error::InvalidStencil(path.to_path_buf(), message.to_string());
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf{
+        return std::env::temp_dir().join(format!("rip-stencil-{}-{}.{}", std::process::id(), name, STENCIL_EXTENSION));
+    }
+
+    // Indices spread over the whole u32 range, so every byte of the label gets exercised.
+    fn test_stencil() -> Stencil{
+        let (width, height) = (7, 5);
+        let labels = (0..width * height).map(|i: u32| i.wrapping_mul(2_654_435_761)).collect();
+        return Stencil{width, height, labels};
+    }
+
+    fn write_header(path: &Path, compression: u8, width: u32, height: u32, body: &[u8]){
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[VERSION, compression, 0, 0]);
+        bytes.extend_from_slice(&width.to_le_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        bytes.extend_from_slice(body);
+        fs::write(path, bytes).unwrap();
+    }
+
+    fn assert_invalid(path: &Path){
+        let result = load_stencil(path);
+        fs::remove_file(path).unwrap();
+        assert!(matches!(result, Err(Error::InvalidStencil(..))), "{:?}", result.map(|stencil| (stencil.width, stencil.height)));
+    }
+
+    #[test]
+    fn round_trips(){
+        for compress in [false, true]{
+            let path = temp_path(&format!("round-trip-{}", compress));
+            save_stencil(test_stencil(), path.clone(), compress).unwrap();
+            let loaded = load_stencil(&path);
+            fs::remove_file(&path).unwrap();
+
+            let (loaded, expected) = (loaded.unwrap(), test_stencil());
+            assert_eq!((loaded.width, loaded.height), (expected.width, expected.height));
+            assert_eq!(loaded, expected);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_magic(){
+        let path = temp_path("bad-magic");
+        fs::write(&path, b"NOTASTENCILFILEATALL").unwrap();
+        assert_invalid(&path);
+    }
+
+    #[test]
+    fn rejects_short_header(){
+        let path = temp_path("short-header");
+        fs::write(&path, MAGIC).unwrap();
+        assert_invalid(&path);
+    }
+
+    #[test]
+    fn rejects_truncated_labels(){
+        for compress in [false, true]{
+            let path = temp_path(&format!("truncated-{}", compress));
+            save_stencil(test_stencil(), path.clone(), compress).unwrap();
+            let bytes = fs::read(&path).unwrap();
+            fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+            assert_invalid(&path);
+        }
+    }
+
+    // A corrupt header mustn't be able to ask for gigabytes up front.
+    #[test]
+    fn rejects_oversized_header(){
+        let path = temp_path("oversized-plain");
+        write_header(&path, 0, 100_000, 100_000, &[0; 16]);
+        assert_invalid(&path);
+
+        let path = temp_path("oversized-zlib");
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0; 16]).unwrap();
+        write_header(&path, 1, 100_000, 100_000, &encoder.finish().unwrap());
+        assert_invalid(&path);
+    }
+
+    // Only images are limited to RGB sized indices, .stencil files take any u32.
+    #[test]
+    fn images_reject_indices_past_the_rgb_limit(){
+        let path = temp_path("past-rgb-limit");
+        write_header(&path, 0, 1, 2, &[(MAX_RGB_INDEX + 1).to_le_bytes(), u32::MAX.to_le_bytes()].concat());
+        let result = load_stencil(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap().labels, vec![MAX_RGB_INDEX + 1, u32::MAX]);
+
+        let image_path = std::env::temp_dir().join(format!("rip-stencil-{}-past-rgb-limit.png", std::process::id()));
+        let result = save_stencil(Stencil{width: 1, height: 1, labels: vec![MAX_RGB_INDEX + 1]}, image_path.clone(), false);
+        assert!(matches!(result, Err(Error::Unsupported(_))));
+        assert!(!image_path.exists());
+    }
+
+    #[test]
+    fn reads_strips(){
+        for compress in [false, true]{
+            let path = temp_path(&format!("strips-{}", compress));
+            save_stencil(test_stencil(), path.clone(), compress).unwrap();
+
+            let mut reader = StencilStripReader::open(&path).unwrap();
+            let (mut labels, mut strip) = (Vec::new(), Vec::new());
+            while reader.read_strip(2, &mut strip).unwrap() > 0{
+                labels.extend_from_slice(&strip);
+            }
+            fs::remove_file(&path).unwrap();
+            assert_eq!(labels, test_stencil().labels);
+        }
+    }
+
+    #[test]
+    fn rejects_extra_labels(){
+        let path = temp_path("extra-zlib");
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0; 20]).unwrap();
+        write_header(&path, 1, 2, 2, &encoder.finish().unwrap());
+        assert_invalid(&path);
+    }
+}
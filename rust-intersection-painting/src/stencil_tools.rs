@@ -0,0 +1,331 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::iter;
+
+use rayon::prelude::*;
+
+use crate::error::{Error, Result};
+use crate::generators::{self, Connectivity};
+use crate::image_tools::RawImage;
+use crate::stencil_file::Stencil;
+use crate::stenciler::{self, BoundingBox, ColourSpace};
+
+// Summary statistics for a stencil, to catch generators producing empty indices or slivers.
+#[derive(Debug, Clone)]
+pub struct StencilReport{
+    pub width: u32,
+    pub height: u32,
+    // Max index + 1, which is how many segments the pipeline allocates for.
+    pub count_segments: usize,
+    pub unused_indices: usize,
+    pub min_area: u64,
+    pub max_area: u64,
+    pub mean_area: f64,
+    // Only used indices get an entry.
+    pub segments: Vec<SegmentReport>
+}
+
+#[derive(Debug, Clone)]
+pub struct SegmentReport{
+    pub index: usize,
+    pub area: u64,
+    // More than one means the segment is split into separate (4-connected) pieces.
+    pub components: u32,
+    pub bounding_box: BoundingBox
+}
+
+impl StencilReport{
+    pub fn used_segments(&self) -> usize{
+        return self.segments.len();
+    }
+
+    pub fn disconnected_segments(&self) -> impl Iterator<Item = &SegmentReport>{
+        return self.segments.iter().filter(|segment| segment.components > 1);
+    }
+
+    // Written by hand, since it's flat and only has numbers in it.
+    pub fn to_json(&self) -> String{
+        let bounding_box_json = |bb: &BoundingBox| format!("{{\"top\": {}, \"bot\": {}, \"left\": {}, \"right\": {}}}", bb.top, bb.bot, bb.left, bb.right);
+        let segments: Vec<String> = self.segments.iter().map(|segment| {
+            format!("    {{\"index\": {}, \"area\": {}, \"components\": {}, \"bounding_box\": {}}}", segment.index, segment.area, segment.components, bounding_box_json(&segment.bounding_box))
+        }).collect();
+        let disconnected: Vec<String> = self.disconnected_segments().map(|segment| segment.index.to_string()).collect();
+
+        return format!(
+            "{{\n  \"width\": {},\n  \"height\": {},\n  \"count_segments\": {},\n  \"used_segments\": {},\n  \"unused_indices\": {},\n  \"min_area\": {},\n  \"max_area\": {},\n  \"mean_area\": {},\n  \"disconnected_segments\": [{}],\n  \"segments\": [\n{}\n  ]\n}}",
+            self.width, self.height, self.count_segments, self.used_segments(), self.unused_indices, self.min_area, self.max_area, self.mean_area,
+            disconnected.join(", "), segments.join(",\n")
+        );
+    }
+}
+
+impl fmt::Display for StencilReport{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Size: {}x{}", self.width, self.height)?;
+        writeln!(f, "Segments: {} used of {} indices ({} unused)", self.used_segments(), self.count_segments, self.unused_indices)?;
+        writeln!(f, "Area: min {}, max {}, mean {:.1}", self.min_area, self.max_area, self.mean_area)?;
+
+        let disconnected: Vec<&SegmentReport> = self.disconnected_segments().collect();
+        writeln!(f, "Disconnected segments: {}", disconnected.len())?;
+        for segment in disconnected{
+            writeln!(f, "  {}: {} pieces", segment.index, segment.components)?;
+        }
+
+        writeln!(f, "Bounding boxes (index: left, top - right, bot):")?;
+        for segment in self.segments.iter(){
+            let bb = segment.bounding_box;
+            writeln!(f, "  {}: {}, {} - {}, {}", segment.index, bb.left, bb.top, bb.right, bb.bot)?;
+        }
+        return Ok(());
+    }
+}
+
+pub fn inspect_stencil(stencil: &Stencil) -> Result<StencilReport>{
+    let bounding_boxes = stenciler::segment_bounding_boxes(stencil);
+    let count_segments = bounding_boxes.len();
+
+    // Every connected piece gets its own index from the flood fill, so counting the distinct pieces seen in each segment finds the split ones.
+    let pieces = generators::fill_bucket_grid(stencil, Connectivity::Four)?;
+    let mut areas = vec![0u64; count_segments];
+    let mut components = vec![0u32; count_segments];
+    let mut piece_seen: Vec<bool> = vec![];
+
+    for (&index, &piece) in iter::zip(&stencil.labels, &pieces){
+        let (index, piece) = (index as usize, piece as usize);
+        areas[index] += 1;
+
+        if piece >= piece_seen.len(){
+            piece_seen.resize(piece + 1, false);
+        }
+        if !piece_seen[piece]{
+            piece_seen[piece] = true;
+            components[index] += 1;
+        }
+    }
+
+    let segments: Vec<SegmentReport> = bounding_boxes.iter().enumerate().filter_map(|(index, bounding_box)| {
+        bounding_box.map(|bounding_box| SegmentReport{index, area: areas[index], components: components[index], bounding_box})
+    }).collect();
+
+    let total_area: u64 = segments.iter().map(|segment| segment.area).sum();
+    return Ok(StencilReport{
+        width: stencil.width,
+        height: stencil.height,
+        count_segments,
+        unused_indices: count_segments - segments.len(),
+        min_area: segments.iter().map(|segment| segment.area).min().unwrap_or(0),
+        max_area: segments.iter().map(|segment| segment.area).max().unwrap_or(0),
+        mean_area: if segments.is_empty() { 0.0 } else { total_area as f64 / segments.len() as f64 },
+        segments
+    });
+}
+
+// Gives every connected piece of every segment its own index, so one segment can't average over distant regions.
+// Fails rather than reusing indices if there are more pieces than a stencil can number.
+pub fn split_components(stencil: &Stencil, connectivity: Connectivity) -> Result<Stencil>{
+    return Ok(Stencil{width: stencil.width, height: stencil.height, labels: generators::fill_bucket_grid(stencil, connectivity)?});
+}
+
+// Which indices in 0..=max index actually have pixels.
+pub fn used_indices(stencil: &Stencil) -> Vec<bool>{
+    let mut used: Vec<bool> = vec![];
+    for &index in &stencil.labels{
+        let index = index as usize;
+        if index >= used.len(){
+            used.resize(index + 1, false);
+        }
+        used[index] = true;
+    }
+    return used;
+}
+
+// Maps the used indices onto 0..n, keeping their order. Dense stencils come back unchanged.
+pub fn relabel_stencil(stencil: &Stencil) -> Stencil{
+    return relabel_with(stencil, &used_indices(stencil));
+}
+
+pub(crate) fn relabel_with(stencil: &Stencil, used: &[bool]) -> Stencil{
+    let mut new_indices = vec![0u32; used.len()];
+    for (next_index, (new_index, _)) in iter::zip(new_indices.iter_mut(), used).filter(|(_, &is_used)| is_used).enumerate(){
+        *new_index = next_index as u32;
+    }
+
+    let labels = stencil.labels.par_iter().map(|&label| new_indices[label as usize]).collect();
+    return Stencil{width: stencil.width, height: stencil.height, labels};
+}
+
+// Merges every segment smaller than `min_area` pixels into a neighbour, smallest first.
+// Without an image it goes to the largest neighbour. With one, it goes to the neighbour with the closest mean colour (in OKLab).
+pub fn merge_small_segments(stencil: &Stencil, min_area: u64, image: Option<&RawImage>) -> Result<Stencil>{
+    if let Some(image) = image{
+        if image.width != stencil.width || image.height != stencil.height{
+            return Err(Error::DimensionMismatch{stencil: (stencil.width, stencil.height), image: (image.width, image.height)});
+        }
+    }
+
+    let labels = relabel_stencil(stencil).labels;
+    let num_segments = labels.iter().max().map_or(0, |&max| max as usize + 1);
+    let width = stencil.width as usize;
+
+    let mut areas = vec![0u64; num_segments];
+    // Shared boundary length with each neighbouring segment.
+    let mut neighbours: Vec<HashMap<u32, u32>> = vec![HashMap::new(); num_segments];
+    for (pixel, &label) in labels.iter().enumerate(){
+        areas[label as usize] += 1;
+
+        let right = (pixel % width + 1 < width).then(|| labels[pixel + 1]);
+        let below = labels.get(pixel + width).copied();
+        for neighbour in [right, below].into_iter().flatten().filter(|&neighbour| neighbour != label){
+            *neighbours[label as usize].entry(neighbour).or_insert(0) += 1;
+            *neighbours[neighbour as usize].entry(label).or_insert(0) += 1;
+        }
+    }
+
+    let mut colours: Option<Vec<[f32; 4]>> = image.map(|image| {
+        let mut sum_vec = vec![0i64; num_segments * 4];
+        let mut count_vec = vec![0u32; num_segments];
+        stenciler::accumulate_perceptual_means(&labels, &image.data, true, ColourSpace::Oklab, &mut sum_vec, &mut count_vec);
+        // Scale alpha to match OKLab's roughly [0, 1] range.
+        stenciler::finish_perceptual_means(&sum_vec, &count_vec).into_iter().map(|mean| {
+            let mean = mean.unwrap_or([0.0; 4]);
+            [mean[0], mean[1], mean[2], mean[3] / 255.0]
+        }).collect()
+    });
+    let distance = |a: &[f32; 4], b: &[f32; 4]| iter::zip(a, b).map(|(a, b)| (a - b) * (a - b)).sum::<f32>();
+
+    // Merged segments point at the segment they went into.
+    let mut merged_into: Vec<u32> = (0..num_segments as u32).collect();
+    let mut queue: BinaryHeap<Reverse<(u64, u32)>> = areas.iter().enumerate().filter(|(_, &area)| area < min_area).map(|(segment, &area)| Reverse((area, segment as u32))).collect();
+
+    while let Some(Reverse((area, segment))) = queue.pop(){
+        // Skip stale entries for segments that have since been merged or grown.
+        if merged_into[segment as usize] != segment || areas[segment as usize] != area{
+            continue;
+        }
+
+        let target = match &colours{
+            Some(colours) => neighbours[segment as usize].keys().min_by(|&&a, &&b| {
+                distance(&colours[segment as usize], &colours[a as usize]).total_cmp(&distance(&colours[segment as usize], &colours[b as usize])).then(a.cmp(&b))
+            }),
+            None => neighbours[segment as usize].keys().max_by_key(|&&neighbour| (areas[neighbour as usize], Reverse(neighbour)))
+        };
+        // A segment with no neighbours covers the whole image, so there's nowhere to put it.
+        let Some(&target) = target else { continue };
+
+        if let Some(colours) = colours.as_mut(){
+            let (segment_weight, target_weight) = (area as f32, areas[target as usize] as f32);
+            let merged: Vec<f32> = iter::zip(colours[segment as usize], colours[target as usize]).map(|(s, t)| (s * segment_weight + t * target_weight) / (segment_weight + target_weight)).collect();
+            colours[target as usize].copy_from_slice(&merged);
+        }
+        areas[target as usize] += area;
+        merged_into[segment as usize] = target;
+
+        // The target inherits the segment's borders.
+        for (neighbour, length) in std::mem::take(&mut neighbours[segment as usize]){
+            neighbours[neighbour as usize].remove(&segment);
+            if neighbour != target{
+                *neighbours[neighbour as usize].entry(target).or_insert(0) += length;
+                *neighbours[target as usize].entry(neighbour).or_insert(0) += length;
+            }
+        }
+
+        if areas[target as usize] < min_area{
+            queue.push(Reverse((areas[target as usize], target)));
+        }
+    }
+
+    // Follow each chain of merges to where it ended up.
+    for segment in 0..num_segments{
+        let mut root = merged_into[segment];
+        while merged_into[root as usize] != root{
+            root = merged_into[root as usize];
+        }
+        merged_into[segment] = root;
+    }
+
+    let labels = labels.iter().map(|&label| merged_into[label as usize]).collect();
+    return Ok(relabel_stencil(&Stencil{width: stencil.width, height: stencil.height, labels}));
+}
+
+// The common refinement of two stencils: every distinct (first index, second index) pair gets its own segment, numbered in the order they're found.
+pub fn combine_stencils(first: &Stencil, second: &Stencil) -> Result<Stencil>{
+    if first.width != second.width || first.height != second.height{
+        return Err(Error::DimensionMismatch{stencil: (first.width, first.height), image: (second.width, second.height)});
+    }
+
+    let mut pairs: HashMap<(u32, u32), u32> = HashMap::new();
+    let mut labels = Vec::with_capacity(first.labels.len());
+
+    for pair in iter::zip(first.labels.iter().copied(), second.labels.iter().copied()){
+        let next_index = pairs.len();
+        if next_index as u64 > u32::MAX as u64{
+            return Err(Error::Unsupported(format!("combining these stencils makes more than {} segments", u32::MAX as u64 + 1)));
+        }
+        labels.push(*pairs.entry(pair).or_insert(next_index as u32));
+    }
+    return Ok(Stencil{width: first.width, height: first.height, labels});
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // Five pixels of 0 on the left, nine of 1 on the right, and a one pixel island of 2 touching both.
+    fn island_stencil() -> Stencil{
+        return Stencil{width: 5, height: 3, labels: vec![
+            0, 0, 1, 1, 1,
+            0, 2, 1, 1, 1,
+            0, 0, 1, 1, 1
+        ]};
+    }
+
+    #[test]
+    fn merges_into_the_largest_neighbour(){
+        let merged = merge_small_segments(&island_stencil(), 2, None).unwrap();
+        assert_eq!(merged.labels, vec![
+            0, 0, 1, 1, 1,
+            0, 1, 1, 1, 1,
+            0, 0, 1, 1, 1
+        ]);
+    }
+
+    #[test]
+    fn merges_into_the_closest_colour(){
+        let (left, right) = ([200, 30, 30, 255], [20, 40, 220, 255]);
+        let island = island_stencil();
+        // The island is a slightly darker red, so it belongs with the smaller left segment.
+        let data = island.labels.iter().flat_map(|&label| match label{
+            0 => left,
+            1 => right,
+            _ => [180, 20, 20, 255]
+        }).collect();
+        let image = RawImage{width: 5, height: 3, data};
+
+        let merged = merge_small_segments(&island, 2, Some(&image)).unwrap();
+        assert_eq!(merged.labels, vec![
+            0, 0, 1, 1, 1,
+            0, 0, 1, 1, 1,
+            0, 0, 1, 1, 1
+        ]);
+    }
+
+    #[test]
+    fn combines_into_the_common_refinement(){
+        let columns = Stencil{width: 4, height: 2, labels: vec![5, 5, 9, 9, 5, 5, 9, 9]};
+        let rows = Stencil{width: 4, height: 2, labels: vec![0, 0, 0, 0, 1, 1, 1, 1]};
+        assert_eq!(combine_stencils(&columns, &rows).unwrap().labels, vec![0, 0, 1, 1, 2, 2, 3, 3]);
+
+        let other_size = Stencil{width: 2, height: 4, labels: rows.labels.clone()};
+        assert!(matches!(combine_stencils(&columns, &other_size), Err(Error::DimensionMismatch{..})));
+    }
+
+    #[test]
+    fn relabels_densely_in_order(){
+        let sparse = Stencil{width: 5, height: 1, labels: vec![7, 3, 7, 42, 3]};
+        assert_eq!(relabel_stencil(&sparse).labels, vec![1, 0, 1, 2, 0]);
+
+        let dense = Stencil{width: 4, height: 1, labels: vec![2, 0, 1, 2]};
+        assert_eq!(relabel_stencil(&dense), dense);
+    }
+}
@@ -1,109 +1,543 @@
 use std::fmt;
+use crate::colour;
+use crate::stencil_tools;
+use crate::error::{Error, Result};
 use crate::image_tools::RawImage;
-use crate::generators::BYTES_PER_PIXEL;
+use crate::stencil_file::Stencil;
+use crate::generators::{BYTES_PER_PIXEL, Connectivity};
 use std::iter;
+use std::ops::Range;
+use rayon::prelude::*;
+use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
 
 // Utilities:
-pub (in crate) fn rgb_to_index(r: u8, g: u8, b: u8) -> usize{
+/// The segment index stored in a stencil pixel's R, G and B bytes, least significant byte first. Alpha is ignored.
+/// This is the inverse of `generators::segment_index_to_rgb`.
+pub fn rgb_to_index(r: u8, g: u8, b: u8) -> usize{
     return (r as usize) + (g as usize) * 256 + (b as usize) * 256 * 256;
 }
 
+// How the line image gets painted through the stencil.
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineOptions{
+    pub alpha_averaging: bool,
+    pub aggregation: Aggregation,
+    pub colour_space: ColourSpace,
+    pub min_coverage: Option<Coverage>,
+    pub background: Background,
+    pub outline: Option<OutlineOptions>,
+    // Split segments into their connected pieces before painting.
+    pub split_components: Option<Connectivity>
+}
+
+impl Default for PipelineOptions{
+    fn default() -> Self{
+        PipelineOptions{
+            alpha_averaging: false,
+            aggregation: Aggregation::Mean,
+            colour_space: ColourSpace::Srgb,
+            min_coverage: None,
+            background: Background::Colour([0, 0, 0, 255]),
+            outline: None,
+            split_components: None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Aggregation{
+    Mean,
+    Median,
+    Mode,
+    Min,
+    Max
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColourSpace{
+    Srgb,
+    Linear,
+    Oklab
+}
+
+// Ink needed before a segment gets filled, either as a fraction of its area or in pixels.
+#[derive(Debug, Clone, Copy)]
+pub enum Coverage{
+    Fraction(f32),
+    Pixels(u32)
+}
+
+// What unfilled segments look like.
+#[derive(Debug, Clone, Copy)]
+pub enum Background{
+    Colour([u8; 4]),
+    Passthrough
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OutlineOptions{
+    pub colour: [u8; 4],
+    pub thickness: u32,
+    pub segments: OutlineSegments
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutlineSegments{
+    All,
+    Painted
+}
+
 // CPU Pipeline
-pub fn cpu_pipeline(grid_image: &RawImage, alpha_averaging: bool, line_image: &RawImage) -> RawImage{
+pub fn cpu_pipeline(grid_image: &Stencil, options: &PipelineOptions, line_image: &RawImage) -> Result<RawImage>{
     if grid_image.width != line_image.width || grid_image.height != line_image.height{
-        panic!("Grid Image Dims ({}, {}) != Line Image Dims ({}, {})", grid_image.width, grid_image.height, line_image.width, line_image.height);
+        return Err(Error::DimensionMismatch{stencil: (grid_image.width, grid_image.height), image: (line_image.width, line_image.height)});
     }
 
+    let split;
+    let grid_image = match options.split_components{
+        Some(connectivity) => {
+            split = stencil_tools::split_components(grid_image, connectivity)?;
+            &split
+        },
+        None => grid_image
+    };
+
+    // Sparse stencils would waste most of the per segment tables, so squash their indices together first.
+    let used = stencil_tools::used_indices(grid_image);
+    let relabelled;
+    let grid_image = if used.contains(&false){
+        relabelled = stencil_tools::relabel_with(grid_image, &used);
+        &relabelled
+    }
+    else{
+        grid_image
+    };
+
     let num_segments = count_segments(grid_image);
-    let averages =  cpu_averager(grid_image, num_segments, alpha_averaging, line_image);
-    let buffer = cpu_render_to_buffer(grid_image, &averages);
+    // Averages come back in whichever colour space they were taken in, and are None for unfilled segments.
+    let (mut averages, colour_space) = match (options.aggregation, options.colour_space){
+        (Aggregation::Mean, ColourSpace::Srgb) => (cpu_averager(grid_image, num_segments, options.alpha_averaging, line_image), ColourSpace::Srgb),
+        (Aggregation::Mean, colour_space) => (cpu_perceptual_averager(grid_image, num_segments, options.alpha_averaging, colour_space, line_image), colour_space),
+        (aggregation, _) => (cpu_aggregator(grid_image, num_segments, options.alpha_averaging, aggregation, line_image), ColourSpace::Srgb)
+    };
+
+    if let Some(min_coverage) = options.min_coverage{
+        let covered = cpu_coverage(grid_image, num_segments, min_coverage, line_image);
+        for (average, is_covered) in iter::zip(averages.iter_mut(), covered){
+            if !is_covered{
+                *average = None;
+            }
+        }
+    }
 
-    return RawImage { width: grid_image.width, height: grid_image.height, data: buffer }
+    let mut buffer = cpu_render_to_buffer(grid_image, &averages, colour_space, options.background, line_image);
+
+    if let Some(outline) = options.outline{
+        let painted: Vec<bool> = averages.iter().map(|average| average.is_some()).collect();
+        cpu_draw_outlines(grid_image, &painted, &outline, &mut buffer);
+    }
+
+    return Ok(RawImage { width: grid_image.width, height: grid_image.height, data: buffer })
 }
 
-fn count_segments(image: &RawImage) -> usize{
-    let mut max = 0;
-    let mut im_index: usize = 0;
+// Splits a stencil into one band of rows per thread, as ranges of pixels.
+// Each band gets its own partial sums, so more bands than threads would only cost memory.
+fn row_bands(image: &Stencil) -> Vec<Range<usize>>{
+    let row_len = image.width as usize;
+    let rows_per_band = num::Integer::div_ceil(&(image.height as usize), &rayon::current_num_threads()).max(1);
 
-    for _y in 0..image.height{
-        for _x in 0..image.width{
-            let segment_index = rgb_to_index(image.data[im_index], image.data[im_index+1], image.data[im_index+2]);
+    let mut bands: Vec<Range<usize>> = (0..image.height as usize).step_by(rows_per_band).map(|start_row| {
+        let end_row = (start_row + rows_per_band).min(image.height as usize);
+        start_row * row_len..end_row * row_len
+    }).collect();
 
-            im_index += BYTES_PER_PIXEL as usize; 
-            if segment_index > max{
-                max = segment_index;
-            }
-        }
+    if bands.is_empty(){
+        bands.push(0..0);
     }
+    return bands;
+}
+
+// The line image bytes that go with a range of stencil pixels.
+fn line_bytes(pixels: &Range<usize>) -> Range<usize>{
+    return pixels.start * BYTES_PER_PIXEL as usize..pixels.end * BYTES_PER_PIXEL as usize;
+}
+
+fn count_segments(image: &Stencil) -> usize{
+    let max = row_bands(image).into_par_iter().map(|band| max_segment_index(&image.labels[band])).max().unwrap_or(0);
 
     return max + 1;
 }
 
-fn cpu_averager(grid_image: &RawImage, num_segments: usize, alpha_averaging: bool, line_image: &RawImage) -> Vec<u8>{
-    let mut sum_vec: Vec<u64> = vec![0; num_segments * 4];
-    let mut count_vec: Vec<u32> = vec![0; num_segments];
-
-    let mut grid_index: usize = 0;
-    let mut line_index: usize = 0;
-    
-    for _y in 0..grid_image.height{
-        for _x in 0..grid_image.width{
-            let segment_index = rgb_to_index(grid_image.data[grid_index], grid_image.data[grid_index + 1], grid_image.data[grid_index + 2]);
-            let sum_index = segment_index * 4;
-            grid_index += BYTES_PER_PIXEL as usize;
-
-            if alpha_averaging{
-                sum_vec[sum_index] += line_image.data[line_index] as u64;
-                sum_vec[sum_index + 1] += line_image.data[line_index + 1] as u64;
-                sum_vec[sum_index + 2] += line_image.data[line_index + 2] as u64;
-                sum_vec[sum_index + 3] += line_image.data[line_index + 3] as u64;
+// The building blocks below work on matching slices of stencil labels and line image data, so they can be used on bands or strips.
+pub(crate) fn max_segment_index(grid: &[u32]) -> usize{
+    return grid.iter().max().map_or(0, |&max| max as usize);
+}
+
+fn cpu_averager(grid_image: &Stencil, num_segments: usize, alpha_averaging: bool, line_image: &RawImage) -> Vec<Option<[f32; 4]>>{
+    // Integer sums don't care what order they're added in, so the bands merge to exactly the sequential result.
+    let (sum_vec, count_vec) = row_bands(grid_image).into_par_iter().map(|band| {
+        let mut sum_vec: Vec<u64> = vec![0; num_segments * 4];
+        let mut count_vec: Vec<u32> = vec![0; num_segments];
+        accumulate_means(&grid_image.labels[band.clone()], &line_image.data[line_bytes(&band)], alpha_averaging, &mut sum_vec, &mut count_vec);
+        (sum_vec, count_vec)
+    }).reduce_with(merge_partial_sums).expect("There's always at least one band");
+
+    return finish_means(&sum_vec, &count_vec);
+}
+
+pub(crate) fn accumulate_means(grid: &[u32], line: &[u8], alpha_averaging: bool, sum_vec: &mut [u64], count_vec: &mut [u32]){
+    for (&segment_index, pixel) in iter::zip(grid, line.chunks_exact(BYTES_PER_PIXEL as usize)){
+        let segment_index = segment_index as usize;
+        let sum_index = segment_index * 4;
+
+        if alpha_averaging{
+            sum_vec[sum_index] += pixel[0] as u64;
+            sum_vec[sum_index + 1] += pixel[1] as u64;
+            sum_vec[sum_index + 2] += pixel[2] as u64;
+            sum_vec[sum_index + 3] += pixel[3] as u64;
+            count_vec[segment_index] += 1
+        }
+        else{
+            if pixel[3] != 0{
+                sum_vec[sum_index] += pixel[0] as u64;
+                sum_vec[sum_index + 1] += pixel[1] as u64;
+                sum_vec[sum_index + 2] += pixel[2] as u64;
+                sum_vec[sum_index + 3] += 255;
                 count_vec[segment_index] += 1
             }
-            else{
-                if line_image.data[line_index + 3] != 0{
-                    sum_vec[sum_index] += line_image.data[line_index] as u64;
-                    sum_vec[sum_index + 1] += line_image.data[line_index + 1] as u64;
-                    sum_vec[sum_index + 2] += line_image.data[line_index + 2] as u64;
-                    sum_vec[sum_index + 3] += 255 as u64;
-                    count_vec[segment_index] += 1
-                }
-            }
-            
-            line_index += BYTES_PER_PIXEL as usize;
         }
     }
+}
 
+pub(crate) fn finish_means(sum_vec: &[u64], count_vec: &[u32]) -> Vec<Option<[f32; 4]>>{
     // TODO: Are iterators too slow for my usecase?
-    return iter::zip(sum_vec.chunks(4), count_vec).flat_map(|(sum, count)| {
+    return iter::zip(sum_vec.chunks(4), count_vec).map(|(sum, &count)| {
         if count == 0{
-            return [0, 0, 0, 255];
+            return None;
         }
-        return [(sum[0]/(count as u64)) as u8, (sum[1]/(count as u64)) as u8, (sum[2]/(count as u64)) as u8, (sum[3]/(count as u64)) as u8];
+        return Some([(sum[0]/(count as u64)) as f32, (sum[1]/(count as u64)) as f32, (sum[2]/(count as u64)) as f32, (sum[3]/(count as u64)) as f32]);
     }).collect();
 }
 
-fn cpu_render_to_buffer(grid_image: &RawImage, averages: &Vec<u8>) -> Vec<u8> {
-    let mut ret_vector = vec![0 as u8; (grid_image.width as usize) * (grid_image.height as usize) * BYTES_PER_PIXEL as usize];
+fn merge_partial_sums<T: std::ops::AddAssign + Copy>(mut a: (Vec<T>, Vec<u32>), b: (Vec<T>, Vec<u32>)) -> (Vec<T>, Vec<u32>){
+    for (a_sum, b_sum) in iter::zip(a.0.iter_mut(), b.0){
+        *a_sum += b_sum;
+    }
+    for (a_count, b_count) in iter::zip(a.1.iter_mut(), b.1){
+        *a_count += b_count;
+    }
+    return a;
+}
+
+// Float sums depend on the order they're added in, which would make the output depend on the thread count.
+// Summing in 32.32 fixed point instead keeps things exact. There's room for ~2 billion full intensity pixels per segment.
+const FIXED_POINT_SCALE: f64 = 4294967296.0;
 
-    let mut grid_index = 0;
-    let mut ret_index = 0;
-    
-    for _y in 0..grid_image.height{
-        for _x in 0..grid_image.width{
-            let segment_index = rgb_to_index(grid_image.data[grid_index], grid_image.data[grid_index+1], grid_image.data[grid_index+2]) * BYTES_PER_PIXEL as usize;
-            grid_index += BYTES_PER_PIXEL as usize;
+// Same as cpu_averager, but converts to linear light or OKLab before summing. Alpha is already linear, so it's left in [0, 255].
+fn cpu_perceptual_averager(grid_image: &Stencil, num_segments: usize, alpha_averaging: bool, colour_space: ColourSpace, line_image: &RawImage) -> Vec<Option<[f32; 4]>>{
+    let (sum_vec, count_vec) = row_bands(grid_image).into_par_iter().map(|band| {
+        let mut sum_vec: Vec<i64> = vec![0; num_segments * 4];
+        let mut count_vec: Vec<u32> = vec![0; num_segments];
+        accumulate_perceptual_means(&grid_image.labels[band.clone()], &line_image.data[line_bytes(&band)], alpha_averaging, colour_space, &mut sum_vec, &mut count_vec);
+        (sum_vec, count_vec)
+    }).reduce_with(merge_partial_sums).expect("There's always at least one band");
+
+    return finish_perceptual_means(&sum_vec, &count_vec);
+}
 
-            ret_vector[ret_index] = averages[segment_index];
-            ret_vector[ret_index + 1] = averages[segment_index + 1];
-            ret_vector[ret_index + 2] = averages[segment_index + 2];
-            ret_vector[ret_index + 3] = averages[segment_index + 3];
-            ret_index += BYTES_PER_PIXEL as usize;
+pub(crate) fn accumulate_perceptual_means(grid: &[u32], line: &[u8], alpha_averaging: bool, colour_space: ColourSpace, sum_vec: &mut [i64], count_vec: &mut [u32]){
+    let to_linear = colour::srgb8_to_linear_table();
+    let to_fixed = |c: f32| (c as f64 * FIXED_POINT_SCALE).round() as i64;
+
+    for (&segment_index, pixel) in iter::zip(grid, line.chunks_exact(BYTES_PER_PIXEL as usize)){
+        let segment_index = segment_index as usize;
+        let sum_index = segment_index * 4;
+
+        if !alpha_averaging && pixel[3] == 0{
+            continue;
         }
+
+        let (r, g, b) = (to_linear[pixel[0] as usize], to_linear[pixel[1] as usize], to_linear[pixel[2] as usize]);
+        let (c_0, c_1, c_2) = match colour_space{
+            ColourSpace::Oklab => colour::linear_rgb_to_oklab(r, g, b),
+            _ => (r, g, b)
+        };
+
+        sum_vec[sum_index] += to_fixed(c_0);
+        sum_vec[sum_index + 1] += to_fixed(c_1);
+        sum_vec[sum_index + 2] += to_fixed(c_2);
+        sum_vec[sum_index + 3] += if alpha_averaging { pixel[3] as i64 } else { 255 };
+        count_vec[segment_index] += 1;
     }
+}
+
+pub(crate) fn finish_perceptual_means(sum_vec: &[i64], count_vec: &[u32]) -> Vec<Option<[f32; 4]>>{
+    return iter::zip(sum_vec.chunks(4), count_vec).map(|(sum, &count)| {
+        if count == 0{
+            return None;
+        }
+        let count = count as f64;
+        let from_fixed = |c: i64| (c as f64 / FIXED_POINT_SCALE / count) as f32;
+        return Some([from_fixed(sum[0]), from_fixed(sum[1]), from_fixed(sum[2]), (sum[3] as f64 / count) as f32]);
+    }).collect();
+}
+
+// Non-mean aggregations need every pixel of a segment at once, so we bucket the pixels by segment first.
+fn cpu_aggregator(grid_image: &Stencil, num_segments: usize, alpha_averaging: bool, aggregation: Aggregation, line_image: &RawImage) -> Vec<Option<[f32; 4]>>{
+    let counted_pixel = |pixel_index: usize| -> Option<[u8; 4]>{
+        let pixel = &line_image.data[pixel_index * 4..pixel_index * 4 + 4];
+        if alpha_averaging{
+            return Some([pixel[0], pixel[1], pixel[2], pixel[3]]);
+        }
+        if pixel[3] != 0{
+            return Some([pixel[0], pixel[1], pixel[2], 255]);
+        }
+        return None;
+    };
+
+    // First pass counts, so we know where each segment's bucket starts.
+    let mut offsets: Vec<usize> = vec![0; num_segments + 1];
+    for (index, &segment_index) in grid_image.labels.iter().enumerate(){
+        if counted_pixel(index).is_some(){
+            offsets[segment_index as usize + 1] += 1;
+        }
+    }
+
+    for i in 1..offsets.len(){
+        offsets[i] += offsets[i - 1];
+    }
+
+    // Second pass fills the buckets.
+    let mut pixels: Vec<[u8; 4]> = vec![[0; 4]; offsets[num_segments]];
+    let mut next_slot = offsets.clone();
+    for (index, &segment_index) in grid_image.labels.iter().enumerate(){
+        if let Some(pixel) = counted_pixel(index){
+            pixels[next_slot[segment_index as usize]] = pixel;
+            next_slot[segment_index as usize] += 1;
+        }
+    }
+
+    return offsets.windows(2).map(|bounds| {
+        let segment_pixels = &mut pixels[bounds[0]..bounds[1]];
+        if segment_pixels.is_empty(){
+            return None;
+        }
+        return Some(aggregate_segment(segment_pixels, aggregation).map(|c| c as f32));
+    }).collect();
+}
+
+fn aggregate_segment(pixels: &mut [[u8; 4]], aggregation: Aggregation) -> [u8; 4]{
+    let mut ret_val = [0u8; 4];
+
+    match aggregation{
+        Aggregation::Median => {
+            // Histograms beat sorting for u8 channels. Even counts take the lower median.
+            let target = (pixels.len() - 1) / 2;
+            for (channel, out) in ret_val.iter_mut().enumerate(){
+                let mut histogram = [0usize; 256];
+                for pixel in pixels.iter(){
+                    histogram[pixel[channel] as usize] += 1;
+                }
+
+                let mut seen = 0;
+                for (value, count) in histogram.iter().enumerate(){
+                    seen += count;
+                    if seen > target{
+                        *out = value as u8;
+                        break;
+                    }
+                }
+            }
+        },
+        Aggregation::Mode => {
+            // Sort so that equal colours are adjacent, then find the longest run. Ties go to the smallest colour.
+            pixels.sort_unstable_by_key(|p| u32::from_be_bytes(*p));
+
+            let mut best_run = 0;
+            let mut run_start = 0;
+            for i in 1..=pixels.len(){
+                if i == pixels.len() || pixels[i] != pixels[run_start]{
+                    if i - run_start > best_run{
+                        best_run = i - run_start;
+                        ret_val = pixels[run_start];
+                    }
+                    run_start = i;
+                }
+            }
+        },
+        Aggregation::Min => {
+            ret_val = [255; 4];
+            for pixel in pixels.iter(){
+                for channel in 0..4{
+                    ret_val[channel] = ret_val[channel].min(pixel[channel]);
+                }
+            }
+        },
+        Aggregation::Max => {
+            for pixel in pixels.iter(){
+                for channel in 0..4{
+                    ret_val[channel] = ret_val[channel].max(pixel[channel]);
+                }
+            }
+        },
+        Aggregation::Mean => unreachable!("Means are handled by cpu_averager")
+    }
+
+    return ret_val;
+}
+
+// Is there enough ink in each segment to be worth filling?
+fn cpu_coverage(grid_image: &Stencil, num_segments: usize, min_coverage: Coverage, line_image: &RawImage) -> Vec<bool>{
+    let (ink_vec, area_vec) = row_bands(grid_image).into_par_iter().map(|band| {
+        let mut ink_vec: Vec<u32> = vec![0; num_segments];
+        let mut area_vec: Vec<u32> = vec![0; num_segments];
+        accumulate_coverage(&grid_image.labels[band.clone()], &line_image.data[line_bytes(&band)], &mut ink_vec, &mut area_vec);
+        (ink_vec, area_vec)
+    }).reduce_with(merge_partial_sums).expect("There's always at least one band");
+
+    return finish_coverage(&ink_vec, &area_vec, min_coverage);
+}
+
+pub(crate) fn accumulate_coverage(grid: &[u32], line: &[u8], ink_vec: &mut [u32], area_vec: &mut [u32]){
+    for (&segment_index, pixel) in iter::zip(grid, line.chunks_exact(BYTES_PER_PIXEL as usize)){
+        area_vec[segment_index as usize] += 1;
+        if pixel[3] != 0{
+            ink_vec[segment_index as usize] += 1;
+        }
+    }
+}
+
+pub(crate) fn finish_coverage(ink_vec: &[u32], area_vec: &[u32], min_coverage: Coverage) -> Vec<bool>{
+    return iter::zip(ink_vec, area_vec).map(|(&ink, &area)| {
+        match min_coverage{
+            Coverage::Fraction(fraction) => area != 0 && (ink as f32) >= fraction * (area as f32),
+            Coverage::Pixels(pixels) => ink >= pixels
+        }
+    }).collect();
+}
+
+fn cpu_render_to_buffer(grid_image: &Stencil, averages: &[Option<[f32; 4]>], colour_space: ColourSpace, background: Background, line_image: &RawImage) -> Vec<u8> {
+    let palette = srgb_palette(averages, colour_space, background);
+
+    let mut ret_vector = vec![0u8; (grid_image.width as usize) * (grid_image.height as usize) * BYTES_PER_PIXEL as usize];
+    render_rows(&grid_image.labels, &line_image.data, &palette, grid_image.width, &mut ret_vector);
 
     return ret_vector
 }
 
+// Converting back to sRGB once per segment is much cheaper than once per pixel.
+// None means the segment shows the line image through.
+pub(crate) fn srgb_palette(averages: &[Option<[f32; 4]>], colour_space: ColourSpace, background: Background) -> Vec<Option<[u8; 4]>>{
+    return averages.iter().map(|average| {
+        let average = match (average, background){
+            (Some(average), _) => average,
+            (None, Background::Colour(colour)) => return Some(colour),
+            (None, Background::Passthrough) => return None
+        };
+        let (r, g, b) = match colour_space{
+            ColourSpace::Srgb => return Some([average[0] as u8, average[1] as u8, average[2] as u8, average[3] as u8]),
+            ColourSpace::Linear => (average[0], average[1], average[2]),
+            ColourSpace::Oklab => colour::oklab_to_linear_rgb(average[0], average[1], average[2])
+        };
+        return Some([colour::linear_to_srgb8(r), colour::linear_to_srgb8(g), colour::linear_to_srgb8(b), average[3].round() as u8]);
+    }).collect();
+}
+
+pub(crate) fn render_rows(grid: &[u32], line: &[u8], palette: &[Option<[u8; 4]>], width: u32, out: &mut [u8]){
+    // Every pixel is independent, so each row can be written by whichever thread gets to it.
+    let row_len = (width as usize * BYTES_PER_PIXEL as usize).max(1);
+    out.par_chunks_mut(row_len).enumerate().for_each(|(y, row)| {
+        for (grid_index, pixel) in (y * width as usize..).zip(row.chunks_mut(BYTES_PER_PIXEL as usize)){
+            let line_index = grid_index * BYTES_PER_PIXEL as usize;
+            let colour = match palette[grid[grid_index] as usize]{
+                Some(colour) => colour,
+                None => [line[line_index], line[line_index + 1], line[line_index + 2], line[line_index + 3]]
+            };
+
+            pixel.copy_from_slice(&colour);
+        }
+    });
+}
+
+fn cpu_draw_outlines(grid_image: &Stencil, painted: &[bool], outline: &OutlineOptions, buffer: &mut [u8]){
+    let width = grid_image.width as usize;
+    let height = grid_image.height as usize;
+    let segment_at = |pixel: usize| grid_image.labels[pixel] as usize;
+
+    // Mark the top/left pixel of every boundary edge, giving one pixel wide lines.
+    let mut mask = vec![false; width * height];
+    for y in 0..height{
+        for x in 0..width{
+            let pixel = x + y * width;
+            let segment = segment_at(pixel);
+
+            for neighbour in [(x + 1 < width).then_some(pixel + 1), (y + 1 < height).then_some(pixel + width)].into_iter().flatten(){
+                let neighbour_segment = segment_at(neighbour);
+                if neighbour_segment != segment && (outline.segments == OutlineSegments::All || painted[segment] || painted[neighbour_segment]){
+                    mask[pixel] = true;
+                }
+            }
+        }
+    }
+
+    // Then thicken the lines, with a separable box dilation.
+    let before = ((outline.thickness - 1) / 2) as usize;
+    let after = (outline.thickness - 1) as usize - before;
+    if outline.thickness > 1{
+        let mut dilated = vec![false; width * height];
+        for y in 0..height{
+            dilate_line(&mask, &mut dilated, y * width, 1, width, before, after);
+        }
+        for x in 0..width{
+            dilate_line(&dilated, &mut mask, x, width, height, before, after);
+        }
+    }
+
+    for (pixel, is_outline) in mask.iter().enumerate(){
+        if *is_outline{
+            buffer[pixel * 4..pixel * 4 + 4].copy_from_slice(&outline.colour);
+        }
+    }
+}
+
+// Spreads every true in a strided line `before` steps backwards and `after` steps forwards.
+fn dilate_line(mask: &[bool], out: &mut [bool], start: usize, stride: usize, len: usize, before: usize, after: usize){
+    let mut since_true = usize::MAX;
+    for i in 0..len{
+        since_true = if mask[start + i * stride] { 0 } else { since_true.saturating_add(1) };
+        out[start + i * stride] = since_true <= after;
+    }
+
+    let mut until_true = usize::MAX;
+    for i in (0..len).rev(){
+        until_true = if mask[start + i * stride] { 0 } else { until_true.saturating_add(1) };
+        out[start + i * stride] |= until_true <= before;
+    }
+}
+
+// Raw stencils are nearly black, since low indices only use a little of the red channel.
+// This gives every segment its own colour instead, so a stencil can be checked by eye.
+pub fn preview_stencil(grid_image: &Stencil, seed: Option<u64>) -> RawImage{
+    let mut rng = match seed{
+        Some(s) => StdRng::seed_from_u64(s),
+        None => StdRng::from_entropy()
+    };
+
+    // Stepping around the OKLab hue circle by the golden angle keeps any run of colours well spread,
+    // and alternating lightness separates colours that end up with similar hues.
+    let mut palette: Vec<[u8; 4]> = (0..count_segments(grid_image)).map(|k| {
+        let hue = (k as f32 * 0.618034).fract() * std::f32::consts::TAU;
+        let lightness = [0.55, 0.7, 0.85][k % 3];
+        let (r, g, b) = colour::oklab_to_linear_rgb(lightness, 0.13 * hue.cos(), 0.13 * hue.sin());
+        [colour::linear_to_srgb8(r), colour::linear_to_srgb8(g), colour::linear_to_srgb8(b), 255]
+    }).collect();
+    // Neighbouring segments tend to have neighbouring indices, so shuffle to keep them from getting neighbouring colours.
+    palette.shuffle(&mut rng);
+
+    let data = grid_image.labels.iter().flat_map(|&label| palette[label as usize]).collect();
+    return RawImage{width: grid_image.width, height: grid_image.height, data};
+}
+
 // GPU Pipeline: TODO: Complete.
 /*pub fn gpu_pipeline(grid_image: &RawImage, line_image: &RawImage) -> RawImage{
     let bbs = draw_bounding_boxes(grid_image);
@@ -112,26 +546,26 @@ fn cpu_render_to_buffer(grid_image: &RawImage, averages: &Vec<u8>) -> Vec<u8> {
 
 
 // Using the default height/width type for the image library
+// Inclusive on all sides.
 #[derive(Copy, Clone, fmt::Debug)]
-struct BoundingBox{
-    top: u32,
-    bot: u32,
-    left: u32,
-    right: u32
+pub struct BoundingBox{
+    pub top: u32,
+    pub bot: u32,
+    pub left: u32,
+    pub right: u32
 }
 
-#[allow(unused)]
-fn draw_bounding_boxes(image: &RawImage) -> Vec<BoundingBox>{
+// The tightest box around each segment, or None for indices that aren't used.
+pub fn segment_bounding_boxes(image: &Stencil) -> Vec<Option<BoundingBox>>{
     // Time to build our bounding boxes!
     // Bounds checks should be cheaper than integer modulus, so I'm looping over x,y
     let mut cur_ind: usize = 0;
     let mut bounding_boxes: Vec<Option<BoundingBox>> = vec!();
-    let mut max_bb_size: (u32, u32) = (0,0);    // We want our bounding_boxes to be of equal size.
 
     for y in 0..image.height{
         for x in 0..image.width{
-            let index = rgb_to_index(image.data[cur_ind], image.data[cur_ind + 1], image.data[cur_ind + 2]);
-            cur_ind += BYTES_PER_PIXEL as usize;
+            let index = image.labels[cur_ind] as usize;
+            cur_ind += 1;
 
             while index >= bounding_boxes.len(){
                 bounding_boxes.push(None);
@@ -148,21 +582,12 @@ fn draw_bounding_boxes(image: &RawImage) -> Vec<BoundingBox>{
                             bb.bot = y
                         }
 
-                        if bb.bot - bb.top > max_bb_size.1{
-                            max_bb_size = (max_bb_size.0, bb.bot - bb.top);
-                        }
-                        
                         if bb.left > x{
                             bb.left = x
                         }
                         else if bb.right < x{
                             bb.right = x
                         }
-
-                        if bb.right - bb.left > max_bb_size.0{
-                            max_bb_size = (bb.right - bb.left, max_bb_size.1);
-                        }
-
                     },
                     None => *bb_opt = Some(BoundingBox{top: y, bot: y, left: x, right: x})  
                 }
@@ -170,6 +595,19 @@ fn draw_bounding_boxes(image: &RawImage) -> Vec<BoundingBox>{
         }
     }
 
+    return bounding_boxes;
+}
+
+#[allow(unused)]
+fn draw_bounding_boxes(image: &Stencil) -> Vec<BoundingBox>{
+    let bounding_boxes = segment_bounding_boxes(image);
+
+    // We want our bounding_boxes to be of equal size.
+    let mut max_bb_size: (u32, u32) = (0,0);
+    for bb in bounding_boxes.iter().flatten(){
+        max_bb_size = (max_bb_size.0.max(bb.right - bb.left), max_bb_size.1.max(bb.bot - bb.top));
+    }
+
     let mut bounding_boxes: Vec<BoundingBox> = bounding_boxes.iter().map(|x| {
         match x{
             Some(bb) => *bb,
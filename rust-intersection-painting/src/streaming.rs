@@ -0,0 +1,103 @@
+use std::iter;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::image_tools::{PngStripReader, PngStripWriter};
+use crate::stencil_file::StencilStripReader;
+use crate::stenciler::{self, Aggregation, ColourSpace, PipelineOptions};
+
+// Like stenciler::cpu_pipeline, but for images too big to hold in memory.
+// Both images are read in strips of `strip_rows` rows, twice: once to sum up each segment, then again to render.
+// The stencil can be a PNG or a .stencil file. Memory use is a few strips plus the per-segment tables.
+pub fn streaming_pipeline<P: AsRef<Path>>(stencil_path: P, line_path: P, out_path: P, options: &PipelineOptions, strip_rows: u32) -> Result<()>{
+    // Medians and modes need every pixel of a segment at once, and outlines need the rows either side of a strip.
+    if !matches!(options.aggregation, Aggregation::Mean){
+        return Err(Error::Unsupported("streaming only supports the mean aggregation".to_string()));
+    }
+    if options.outline.is_some(){
+        return Err(Error::Unsupported("streaming doesn't support outlines".to_string()));
+    }
+    if options.split_components.is_some(){
+        return Err(Error::Unsupported("streaming can't split segments, since pieces can span strips".to_string()));
+    }
+
+    let (width, height) = check_dimensions(stencil_path.as_ref(), line_path.as_ref())?;
+    let strip_rows = strip_rows.max(1);
+
+    let averages = accumulate_strips(stencil_path.as_ref(), line_path.as_ref(), options, strip_rows)?;
+    let palette = stenciler::srgb_palette(&averages, options.colour_space, options.background);
+
+    let mut stencil = StencilStripReader::open(&stencil_path)?;
+    let mut line = PngStripReader::open(&line_path)?;
+    let mut out = PngStripWriter::create(&out_path, width, height)?;
+    let (mut grid_strip, mut line_strip, mut out_strip) = (Vec::new(), Vec::new(), Vec::new());
+
+    // Pass 2
+    while stencil.read_strip(strip_rows, &mut grid_strip)? > 0{
+        line.read_strip(strip_rows, &mut line_strip)?;
+        out_strip.resize(line_strip.len(), 0);
+        stenciler::render_rows(&grid_strip, &line_strip, &palette, width, &mut out_strip);
+        out.write_strip(&out_strip)?;
+    }
+    return out.finish();
+}
+
+fn check_dimensions(stencil_path: &Path, line_path: &Path) -> Result<(u32, u32)>{
+    let stencil = StencilStripReader::open(stencil_path)?;
+    let line = PngStripReader::open(line_path)?;
+
+    if stencil.width() != line.width() || stencil.height() != line.height(){
+        return Err(Error::DimensionMismatch{stencil: (stencil.width(), stencil.height()), image: (line.width(), line.height())});
+    }
+    return Ok((stencil.width(), stencil.height()));
+}
+
+// Pass 1. We don't know how many segments there are until we've seen every strip, so the tables grow as we go.
+fn accumulate_strips(stencil_path: &Path, line_path: &Path, options: &PipelineOptions, strip_rows: u32) -> Result<Vec<Option<[f32; 4]>>>{
+    let mut stencil = StencilStripReader::open(stencil_path)?;
+    let mut line = PngStripReader::open(line_path)?;
+    let (mut grid_strip, mut line_strip) = (Vec::new(), Vec::new());
+
+    let mut count_vec: Vec<u32> = Vec::new();
+    let mut sum_vec: Vec<u64> = Vec::new();
+    let mut perceptual_sum_vec: Vec<i64> = Vec::new();
+    let mut ink_vec: Vec<u32> = Vec::new();
+    let mut area_vec: Vec<u32> = Vec::new();
+
+    while stencil.read_strip(strip_rows, &mut grid_strip)? > 0{
+        line.read_strip(strip_rows, &mut line_strip)?;
+
+        let num_segments = count_vec.len().max(stenciler::max_segment_index(&grid_strip) + 1);
+        count_vec.resize(num_segments, 0);
+
+        if options.colour_space == ColourSpace::Srgb{
+            sum_vec.resize(num_segments * 4, 0);
+            stenciler::accumulate_means(&grid_strip, &line_strip, options.alpha_averaging, &mut sum_vec, &mut count_vec);
+        }
+        else{
+            perceptual_sum_vec.resize(num_segments * 4, 0);
+            stenciler::accumulate_perceptual_means(&grid_strip, &line_strip, options.alpha_averaging, options.colour_space, &mut perceptual_sum_vec, &mut count_vec);
+        }
+
+        if options.min_coverage.is_some(){
+            ink_vec.resize(num_segments, 0);
+            area_vec.resize(num_segments, 0);
+            stenciler::accumulate_coverage(&grid_strip, &line_strip, &mut ink_vec, &mut area_vec);
+        }
+    }
+
+    let mut averages = match options.colour_space{
+        ColourSpace::Srgb => stenciler::finish_means(&sum_vec, &count_vec),
+        _ => stenciler::finish_perceptual_means(&perceptual_sum_vec, &count_vec)
+    };
+
+    if let Some(min_coverage) = options.min_coverage{
+        let covered = stenciler::finish_coverage(&ink_vec, &area_vec, min_coverage);
+        for (average, is_covered) in iter::zip(averages.iter_mut(), covered){
+            if !is_covered{
+                *average = None;
+            }
+        }
+    }
+    return Ok(averages);
+}